@@ -0,0 +1,29 @@
+use crate::output::{Tabular, Value};
+use serde::Serialize;
+
+/// Something `hiztery check` found out of step, and what fixes it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Problem {
+    pub table: String,
+    pub problem: String,
+    pub rows: i64,
+    pub repair: String,
+    // false for a dry run
+    pub repaired: bool,
+}
+
+impl Tabular for Problem {
+    fn headers() -> Vec<&'static str> {
+        vec!["table", "problem", "rows", "repair", "repaired"]
+    }
+
+    fn cells(&self) -> Vec<Value> {
+        vec![
+            Value::Text(self.table.clone()),
+            Value::Text(self.problem.clone()),
+            Value::Int(self.rows),
+            Value::Text(self.repair.clone()),
+            Value::Text(if self.repaired { "yes" } else { "no" }.to_string()),
+        ]
+    }
+}
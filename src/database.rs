@@ -1,28 +1,50 @@
+use crate::check::Problem;
+use crate::encryption::EncryptedItem;
+use crate::filter::HistoryFilter;
+use crate::fuzzy::{self, FuzzyMatch};
 use crate::history_item::HistoryItem;
+use crate::ignore::IgnoreRules;
+use crate::migrations;
+use crate::perf::{Perf, PerfSummary, Period};
+use crate::prune::{Pruned, RetentionSettings, Rule};
+use crate::scrub::{Scrubbed, Scrubber};
+use crate::session::{self, Session};
+use crate::stats::{self, CommandStats, HeatmapRow, Stats, Top};
 use async_trait::async_trait;
 use chrono::prelude::{DateTime, TimeZone};
 use chrono::Utc;
+use futures::TryStreamExt;
 use itertools::Itertools;
 use log::debug;
+use regex::Regex;
 use sqlx::sqlite::{
     SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions, SqliteRow,
 };
-use sqlx::Row;
+use sqlx::{QueryBuilder, Row};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
 use std::path::Path;
 use std::str::FromStr;
 
 #[async_trait]
 pub trait Database {
-    async fn save(&mut self, h: &HistoryItem) -> Result<(), sqlx::Error>;
+    /// None when the item was filtered out instead of saved.
+    async fn save(&mut self, h: &HistoryItem) -> Result<Option<i64>, sqlx::Error>;
     async fn save_bulk(&mut self, h: &[HistoryItem]) -> Result<(), sqlx::Error>;
-    async fn load(&self, id: &str) -> Result<HistoryItem, sqlx::Error>;
-    async fn list(&self, max: Option<usize>, unique: bool)
-        -> Result<Vec<HistoryItem>, sqlx::Error>;
-    async fn range(
+    /// save_bulk for what sync pulled from `host`. The commands that weren't
+    /// here yet are remembered as coming from there.
+    async fn save_pulled(&mut self, h: &[HistoryItem], host: &str) -> Result<(), sqlx::Error>;
+    /// Which of the history_ids after `after`, up to and including `up_to`,
+    /// were pulled from `host`.
+    async fn pulled_ids(
         &self,
-        from: chrono::DateTime<Utc>,
-        to: chrono::DateTime<Utc>,
-    ) -> Result<Vec<HistoryItem>, sqlx::Error>;
+        host: &str,
+        after: i64,
+        up_to: i64,
+    ) -> Result<HashSet<i64>, sqlx::Error>;
+    async fn load(&self, id: &str) -> Result<HistoryItem, sqlx::Error>;
+    /// Newest first unless the filter asks otherwise.
+    async fn query(&self, filter: &HistoryFilter) -> Result<Vec<HistoryItem>, sqlx::Error>;
     async fn update(&self, h: &HistoryItem) -> Result<(), sqlx::Error>;
     async fn history_count(&self) -> Result<i64, sqlx::Error>;
     async fn first(&self) -> Result<HistoryItem, sqlx::Error>;
@@ -32,18 +54,100 @@ pub trait Database {
         timestamp: chrono::DateTime<Utc>,
         count: i64,
     ) -> Result<Vec<HistoryItem>, sqlx::Error>;
+    /// Only the newest copy of every command that matches both `query` and
+    /// `filter`, best match first.
     async fn search(
         &self,
-        limit: Option<i64>,
         search_mode: SearchMode,
         query: &str,
+        filter: &HistoryFilter,
+    ) -> Result<Vec<HistoryItem>, sqlx::Error>;
+    async fn fuzzy_search(
+        &self,
+        query: &str,
+        filter: &HistoryFilter,
+    ) -> Result<Vec<FuzzyMatch>, sqlx::Error>;
+    /// Like search, but every copy of a command, not just the newest.
+    async fn matches(
+        &self,
+        search_mode: SearchMode,
+        query: &str,
+        filter: &HistoryFilter,
     ) -> Result<Vec<HistoryItem>, sqlx::Error>;
     async fn query_history(&self, query: &str) -> Result<Vec<HistoryItem>, sqlx::Error>;
     async fn delete_history_item(&self, id: i64) -> Result<u64, sqlx::Error>;
+    /// Delete all of `ids` or, if one of them fails, none of them.
+    async fn delete_history_items(&self, ids: &[i64]) -> Result<u64, sqlx::Error>;
+    async fn after(&self, history_id: i64, limit: i64) -> Result<Vec<HistoryItem>, sqlx::Error>;
+    /// Our id as a sync server, made up the first time it's asked for.
+    async fn server_id(&self) -> Result<String, sqlx::Error>;
+    /// Move the sync marks and pulled commands kept for `from` over to `to`,
+    /// unless `to` has marks of its own already.
+    async fn rename_sync_host(&self, from: &str, to: &str) -> Result<(), sqlx::Error>;
+    async fn sync_state(&self, host: &str) -> Result<(i64, i64), sqlx::Error>;
+    async fn set_sync_state(
+        &self,
+        host: &str,
+        last_pushed: i64,
+        last_pulled: i64,
+    ) -> Result<(), sqlx::Error>;
+    async fn save_records(&mut self, records: &[EncryptedItem]) -> Result<usize, sqlx::Error>;
+    async fn records_after(
+        &self,
+        record_id: i64,
+        limit: i64,
+    ) -> Result<Vec<EncryptedItem>, sqlx::Error>;
+    /// Run the scrubber over everything already saved. Returns how many
+    /// commands were redacted and how many dropped.
+    async fn scrub(&self, dry_run: bool) -> Result<(u64, u64), sqlx::Error>;
+    /// Find rows that point at a parent that's gone, and commands the full
+    /// text index is out of step with, and repair them unless `dry_run`.
+    async fn check(&self, dry_run: bool) -> Result<Vec<Problem>, sqlx::Error>;
+    /// Drop what the retention rules say should go, rule by rule, unless
+    /// `dry_run`.
+    async fn prune(
+        &self,
+        retention: &RetentionSettings,
+        dry_run: bool,
+    ) -> Result<Vec<Pruned>, sqlx::Error>;
+    /// Give the space deleted rows took back to the file system.
+    async fn compact(&self) -> Result<(), sqlx::Error>;
+    /// Returns the local session_id, an existing one if the uuid is known.
+    async fn save_session(&mut self, s: &Session) -> Result<i64, sqlx::Error>;
+    async fn end_session(
+        &self,
+        session_id: i64,
+        end_time: chrono::DateTime<Utc>,
+    ) -> Result<u64, sqlx::Error>;
+    /// Look a session up by its uuid or its session_id.
+    async fn session(&self, key: &str) -> Result<Option<Session>, sqlx::Error>;
+    async fn sessions(&self, limit: Option<i64>) -> Result<Vec<Session>, sqlx::Error>;
+    /// The `top` most used, slowest and flakiest commands and the rest of
+    /// `hiztery stats`. Commands run fewer than `min_runs` times are too
+    /// rare to call slow or flaky.
+    async fn stats(
+        &self,
+        filter: &HistoryFilter,
+        top: i64,
+        min_runs: i64,
+    ) -> Result<Stats, sqlx::Error>;
+    /// Record what running `history_id` cost, replacing what's there.
+    async fn save_perf(&self, perf: &Perf) -> Result<(), sqlx::Error>;
+    /// How the runs of exactly `command` that match `filter` performed, per
+    /// period, oldest first.
+    async fn perf(
+        &self,
+        command: &str,
+        by: Period,
+        filter: &HistoryFilter,
+    ) -> Result<Vec<PerfSummary>, sqlx::Error>;
 }
 
+#[derive(Clone)]
 pub struct Sqlite {
     pool: SqlitePool,
+    scrubber: Scrubber,
+    ignore: IgnoreRules,
 }
 
 impl Sqlite {
@@ -51,7 +155,11 @@ impl Sqlite {
         let path = path.as_ref();
         debug!("opening sqlite database at {:?}", path);
 
-        let create = !path.exists();
+        // an in-memory database only lives as long as its connection, so the
+        // pool must hold on to exactly one
+        let in_memory = path.as_os_str() == "sqlite::memory:";
+
+        let create = !in_memory && !path.exists();
         if create {
             if let Some(dir) = path.parent() {
                 std::fs::create_dir_all(dir)?;
@@ -60,65 +168,147 @@ impl Sqlite {
 
         let opts = SqliteConnectOptions::from_str(path.as_os_str().to_str().unwrap())?
             .journal_mode(SqliteJournalMode::Wal)
+            // sqlite leaves them off unless every connection asks, and the
+            // cascades from history_items depend on them
+            .foreign_keys(true)
             .create_if_missing(true);
 
-        let pool = SqlitePoolOptions::new().connect_with(opts).await?;
+        let pool = if in_memory {
+            SqlitePoolOptions::new()
+                .max_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+        } else {
+            SqlitePoolOptions::new()
+        }
+        .connect_with(opts)
+        .await?;
 
         Self::setup_db(&pool).await?;
 
-        Ok(Self { pool })
+        Ok(Self {
+            pool,
+            scrubber: Scrubber::default(),
+            ignore: IgnoreRules::default(),
+        })
+    }
+
+    pub fn with_scrubber(mut self, scrubber: Scrubber) -> Self {
+        self.scrubber = scrubber;
+        self
+    }
+
+    pub fn with_ignore_rules(mut self, ignore: IgnoreRules) -> Self {
+        self.ignore = ignore;
+        self
+    }
+
+    // everything goes through here on the way to save_raw
+    fn filter<'a>(&self, h: &'a HistoryItem) -> Option<Cow<'a, HistoryItem>> {
+        if self.ignore.ignores(&h.command) {
+            return None;
+        }
+
+        let mut h = match self.scrubber.scrub(&h.command) {
+            Scrubbed::Clean => Cow::Borrowed(h),
+            Scrubbed::Redacted(command) => {
+                let mut h = h.clone();
+                h.command = command;
+                Cow::Owned(h)
+            }
+            Scrubbed::Drop => return None,
+        };
+
+        // anything that doesn't say where it ran ran here
+        if h.hostname.is_empty() {
+            h.to_mut().hostname = session::hostname().to_string();
+        }
+        if h.username.is_empty() {
+            h.to_mut().username = session::username().to_string();
+        }
+
+        Some(h)
     }
 
     async fn setup_db(pool: &SqlitePool) -> Result<(), sqlx::Error> {
         debug!("running sqlite database setup");
 
-        // sqlx::migrate!("./migrations").run(pool).await?;
-
-        //TODO: add run_count
-        //TODO: maybe change command to command_line and then
-        // add command with only the command and parameters as
-        // a separate column in order to do interesting queries
-        let history_table = r#"
-        CREATE TABLE IF NOT EXISTS history_items (
-            history_id   INTEGER PRIMARY KEY NOT NULL,
-            timestamp    INTEGER NOT NULL,
-            duration     INTEGER NOT NULL,
-            exit_status  INTEGER NOT NULL,
-            command      TEXT NOT NULL,
-            cwd          TEXT NOT NULL,
-            session_id   INTEGER NOT NULL,
-
-            UNIQUE(timestamp, cwd, command)
-        );
+        migrations::run(pool).await?;
+
+        Ok(())
+    }
 
-        CREATE INDEX IF NOT EXISTS idx_history_timestamp on history_items(timestamp);
-        CREATE INDEX IF NOT EXISTS idx_history_command on history_items(command);"#;
+    // the same command as the one right before it in the same session
+    async fn is_dup(
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        h: &HistoryItem,
+    ) -> Result<bool, sqlx::Error> {
+        let previous: Option<(String,)> = sqlx::query_as(
+            "select command from history_items where session_id = ?1 and timestamp < ?2
+                order by timestamp desc limit 1",
+        )
+        .bind(h.session_id)
+        .bind(h.timestamp.timestamp_nanos())
+        .fetch_optional(&mut *tx)
+        .await?;
 
-        let performance_table = r#"
-        CREATE TABLE IF NOT EXISTS performance_items (
-            perf_id     INTEGER NOT NULL PRIMARY KEY,
-            metrics     FLOAT NOT NULL,
-            history_id  INTEGER NOT NULL
-            REFERENCES history_items(history_id) ON DELETE CASCADE ON UPDATE CASCADE
-          );
-        "#;
+        Ok(previous.is_some_and(|(command,)| command == h.command))
+    }
 
-        let mut conn = pool.acquire().await?;
-        sqlx::query(history_table).execute(&mut conn).await?;
-        sqlx::query(performance_table).execute(&mut conn).await?;
+    // Delete the commands `filter` finds, or with `keep` all the others.
+    // Paging counts from the newest, the same as query.
+    async fn delete_where(
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        filter: &HistoryFilter,
+        keep: bool,
+    ) -> Result<u64, sqlx::Error> {
+        let mut qb = QueryBuilder::new(if keep {
+            "delete from history_items where history_id not in (select h.history_id from history_items h where 1"
+        } else {
+            "delete from history_items where history_id in (select h.history_id from history_items h where 1"
+        });
+        filter.push_conditions(&mut qb, "h");
+        filter.push_order(&mut qb, "h", "h.timestamp desc");
+        filter.push_paging(&mut qb);
+        qb.push(")");
+
+        let res = qb.build().execute(&mut *tx).await?.rows_affected();
 
-        Ok(())
+        Ok(res)
     }
 
     async fn save_raw(
         tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
         h: &HistoryItem,
-    ) -> Result<(), sqlx::Error> {
-        // We don't need the history_id here because it's an auto number field
-        // so it should be ever increasing
+    ) -> Result<i64, sqlx::Error> {
+        // every session_id needs a row in sessions, one nobody started (an
+        // import, or a start run by hand) gets an empty one
         sqlx::query(
-            "insert or ignore into history_items(timestamp, duration, exit_status, command, cwd, session_id)
-                values(?1, ?2, ?3, ?4, ?5, ?6)",
+            "insert into sessions(session_id, uuid, start_time) values(?1, ?2, ?3)
+                on conflict(session_id) do nothing",
+        )
+        .bind(h.session_id)
+        .bind(session::new_uuid())
+        .bind(h.timestamp.timestamp_nanos())
+        .execute(&mut *tx)
+        .await?;
+
+        // history_id is an auto number field and always handed out here, even
+        // to an item that comes with one (a jsonl import). Sync pushes
+        // everything after the last history_id it pushed, so it has to keep
+        // increasing.
+        //
+        // The same command run at the same time in the same place is already
+        // there. A copy of it that was saved while the command was still
+        // running (a sync from another machine) catches up once it has
+        // finished, otherwise it's left alone.
+        let inserted: Option<(i64,)> = sqlx::query_as(
+            "insert into history_items(timestamp, duration, exit_status, command, cwd, session_id, hostname, username)
+                values(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                on conflict(timestamp, cwd, command) do update
+                    set duration = excluded.duration, exit_status = excluded.exit_status
+                    where history_items.duration < 0 and excluded.duration >= 0
+                returning history_id",
         )
         .bind(h.timestamp.timestamp_nanos())
         .bind(h.duration)
@@ -126,10 +316,25 @@ impl Sqlite {
         .bind(h.command.as_str())
         .bind(h.cwd.as_str())
         .bind(h.session_id)
-        .execute(tx)
+        .bind(h.hostname.as_str())
+        .bind(h.username.as_str())
+        .fetch_optional(&mut *tx)
         .await?;
+        if let Some((id,)) = inserted {
+            return Ok(id);
+        }
 
-        Ok(())
+        // nothing changed, hand back the id of the one we collided with
+        let (id,) = sqlx::query_as(
+            "select history_id from history_items where timestamp = ?1 and cwd = ?2 and command = ?3",
+        )
+        .bind(h.timestamp.timestamp_nanos())
+        .bind(h.cwd.as_str())
+        .bind(h.command.as_str())
+        .fetch_one(&mut *tx)
+        .await?;
+
+        Ok(id)
     }
 
     fn convert_time(h: &HistoryItem) {
@@ -152,6 +357,294 @@ impl Sqlite {
         println! {"{}",timestamp_str};
     }
 
+    // Turn what the user typed into an fts5 query against the command column.
+    // Every word is quoted so punctuation like / or - can't be read as fts5
+    // syntax, and a trailing * keeps its meaning as a prefix search.
+    fn fts_query(query: &str) -> Option<String> {
+        let terms = query
+            .split_whitespace()
+            .filter_map(|term| {
+                let (term, prefix) = match term.strip_suffix('*') {
+                    Some(t) => (t, "*"),
+                    None => (term, ""),
+                };
+                if term.is_empty() {
+                    return None;
+                }
+                Some(format!("\"{}\"{}", term.replace('"', "\"\""), prefix))
+            })
+            .join(" ");
+
+        if terms.is_empty() {
+            None
+        } else {
+            Some(format!("command : ({})", terms))
+        }
+    }
+
+    async fn search_fulltext(
+        &self,
+        query: &str,
+        filter: &HistoryFilter,
+    ) -> Result<Vec<HistoryItem>, sqlx::Error> {
+        // an empty query matches everything, same as an empty LIKE pattern
+        let fts_query = match Self::fts_query(query) {
+            Some(q) => q,
+            None => return self.matches(SearchMode::Prefix, "", filter).await,
+        };
+
+        let mut qb = QueryBuilder::new(
+            "select h.* from history_fts
+                join history_items h on h.history_id = history_fts.rowid
+                where history_fts match ",
+        );
+        qb.push_bind(fts_query);
+        filter.push_conditions(&mut qb, "h");
+        filter.push_order(&mut qb, "h", "bm25(history_fts), h.timestamp desc");
+        filter.push_paging(&mut qb);
+
+        let res = qb
+            .build()
+            .map(Self::query_history)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(res)
+    }
+
+    async fn fuzzy_matches(
+        &self,
+        query: &str,
+        filter: &HistoryFilter,
+    ) -> Result<Vec<FuzzyMatch>, sqlx::Error> {
+        // * means "anything" in the other modes, a fuzzy match already skips
+        // whatever it needs to so it can just be dropped
+        let pattern = query.replace('*', "");
+
+        // sqlite does the cheap part: l%s%/ only lets through commands that
+        // contain the pattern as a subsequence. LIKE only ignores ascii case,
+        // so anything else is left to the scorer, which ranks what is left.
+        let mut qb = QueryBuilder::new("select * from history_items h where (h.command like ");
+        qb.push_bind(Self::like_subsequence(&pattern));
+        qb.push(")");
+        filter.push_conditions(&mut qb, "h");
+
+        let keep = filter.wanted().unwrap_or(usize::MAX);
+        let mut best = fuzzy::BestMatches::new(keep);
+        let mut rows = qb.build().map(Self::query_history).fetch(&self.pool);
+        while let Some(item) = rows.try_next().await? {
+            if let Some((score, positions)) = fuzzy::score(&pattern, &item.command) {
+                let closeness = filter.closeness(&item.cwd);
+                best.push(
+                    closeness,
+                    FuzzyMatch {
+                        item,
+                        score,
+                        positions,
+                    },
+                );
+            }
+        }
+
+        // nothing at all, maybe there's a typo in it
+        let variants = fuzzy::typo_variants(&pattern);
+        if best.is_empty() && !variants.is_empty() {
+            let mut qb = QueryBuilder::new("select * from history_items h where (0");
+            for variant in variants {
+                qb.push(" or h.command like ");
+                qb.push_bind(Self::like_subsequence(&variant));
+            }
+            qb.push(")");
+            filter.push_conditions(&mut qb, "h");
+
+            let mut rows = qb.build().map(Self::query_history).fetch(&self.pool);
+            while let Some(item) = rows.try_next().await? {
+                if let Some((score, positions)) = fuzzy::score_typo(&pattern, &item.command) {
+                    let closeness = filter.closeness(&item.cwd);
+                    best.push(
+                        closeness,
+                        FuzzyMatch {
+                            item,
+                            score,
+                            positions,
+                        },
+                    );
+                }
+            }
+        }
+
+        let ranked = best.into_vec();
+        Ok(filter.page(ranked))
+    }
+
+    // %a%b%c% for abc, without the non-ascii characters LIKE can't match
+    // case insensitively
+    fn like_subsequence(pattern: &str) -> String {
+        let ascii: Vec<String> = pattern
+            .chars()
+            .filter(char::is_ascii)
+            .map(String::from)
+            .collect();
+
+        format!("%{}%", ascii.join("%"))
+    }
+
+    // sqlite has no regexp() unless somebody registers one, so the regex is
+    // run here over whatever the filter lets through instead
+    async fn search_regex(
+        &self,
+        query: &str,
+        filter: &HistoryFilter,
+    ) -> Result<Vec<HistoryItem>, sqlx::Error> {
+        let re = Regex::new(query).map_err(|e| sqlx::Error::Configuration(e.into()))?;
+
+        let mut qb = QueryBuilder::new("select * from history_items h where 1");
+        filter.push_conditions(&mut qb, "h");
+        filter.push_order(&mut qb, "h", "h.timestamp desc");
+
+        // in order already, so whatever matches past offset+limit isn't needed
+        let wanted = filter.wanted().unwrap_or(usize::MAX);
+        let mut res = vec![];
+        let mut rows = qb.build().map(Self::query_history).fetch(&self.pool);
+        while res.len() < wanted {
+            match rows.try_next().await? {
+                Some(item) if re.is_match(&item.command) => res.push(item),
+                Some(_) => {}
+                None => break,
+            }
+        }
+
+        Ok(filter.page(res))
+    }
+
+    // the most common values of `expr`, e.g. the command or the cwd
+    async fn top(
+        &self,
+        filter: &HistoryFilter,
+        expr: &str,
+        top: i64,
+    ) -> Result<Vec<Top>, sqlx::Error> {
+        let mut qb = QueryBuilder::new(format!(
+            "select {} as name, count(*) as count from history_items h where 1",
+            expr
+        ));
+        filter.push_conditions(&mut qb, "h");
+        qb.push(
+            " group by name having name != '' order by count desc, max(h.timestamp) desc limit ",
+        )
+        .push_bind(top);
+
+        let res = qb
+            .build()
+            .map(|row: SqliteRow| Top {
+                name: row.get("name"),
+                count: row.get("count"),
+            })
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(res)
+    }
+
+    // Runs, failures and durations per command. The p95 is the nearest rank
+    // one over the runs that reported a duration, sqlite has no percentile
+    // function. `extra` and `order` pick which of them are worth listing.
+    async fn command_stats(
+        &self,
+        filter: &HistoryFilter,
+        extra: &str,
+        order: &str,
+        min_runs: i64,
+        top: i64,
+    ) -> Result<Vec<CommandStats>, sqlx::Error> {
+        let mut qb = QueryBuilder::new(
+            "with runs as (
+                select h.command, count(*) as runs,
+                    sum(h.exit_status not in (0, -1)) as failed,
+                    sum(h.exit_status != -1) as finished
+                from history_items h where 1",
+        );
+        filter.push_conditions(&mut qb, "h");
+        qb.push(" group by h.command having count(*) >= ")
+            .push_bind(min_runs);
+        qb.push(
+            "), timed as (
+                select h.command, h.duration,
+                    avg(h.duration) over (partition by h.command) as avg_duration,
+                    row_number() over (partition by h.command order by h.duration) as rn,
+                    count(*) over (partition by h.command) as n
+                from history_items h where h.duration >= 0",
+        );
+        filter.push_conditions(&mut qb, "h");
+        qb.push(format!(
+            ")
+            select r.command, r.runs, r.failed,
+                coalesce(cast(r.failed as real) / nullif(r.finished, 0), 0.0) as failure_rate,
+                coalesce(cast(t.avg_duration as integer), -1) as avg_duration,
+                coalesce(t.duration, -1) as p95_duration
+            from runs r
+                left join timed t on t.command = r.command and t.rn = (95 * t.n + 99) / 100
+            where {}
+            order by {}
+            limit ",
+            extra, order
+        ))
+        .push_bind(top);
+
+        let res = qb
+            .build()
+            .map(|row: SqliteRow| CommandStats {
+                command: row.get("command"),
+                runs: row.get("runs"),
+                avg_duration: row.get("avg_duration"),
+                p95_duration: row.get("p95_duration"),
+                failed: row.get("failed"),
+                failure_rate: row.get("failure_rate"),
+            })
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(res)
+    }
+
+    // commands per weekday and hour, in local time like the rest of the output
+    async fn heatmap(&self, filter: &HistoryFilter) -> Result<Vec<HeatmapRow>, sqlx::Error> {
+        let mut qb = QueryBuilder::new(
+            "select
+                cast(strftime('%w', h.timestamp / 1000000000, 'unixepoch', 'localtime') as integer) as day,
+                cast(strftime('%H', h.timestamp / 1000000000, 'unixepoch', 'localtime') as integer) as hour,
+                count(*) as count
+            from history_items h where 1",
+        );
+        filter.push_conditions(&mut qb, "h");
+        qb.push(" group by day, hour");
+
+        let mut rows: Vec<HeatmapRow> = stats::DAYS
+            .iter()
+            .map(|day| HeatmapRow {
+                day,
+                hours: vec![0; 24],
+            })
+            .collect();
+        let counts = qb
+            .build()
+            .map(|row: SqliteRow| {
+                (
+                    row.get::<i64, _>("day"),
+                    row.get::<i64, _>("hour"),
+                    row.get::<i64, _>("count"),
+                )
+            })
+            .fetch_all(&self.pool)
+            .await?;
+        for (day, hour, count) in counts {
+            // %w is 0 for sunday
+            rows[(day as usize + 6) % 7].hours[hour as usize] = count;
+        }
+
+        Ok(rows)
+    }
+
     fn query_history(row: SqliteRow) -> HistoryItem {
         HistoryItem {
             history_id: row.get("history_id"),
@@ -161,20 +654,49 @@ impl Sqlite {
             command: row.get("command"),
             cwd: row.get("cwd"),
             session_id: row.get("session_id"),
+            hostname: row.get("hostname"),
+            username: row.get("username"),
+        }
+    }
+
+    fn query_session(row: SqliteRow) -> Session {
+        Session {
+            session_id: row.get("session_id"),
+            uuid: row.get("uuid"),
+            hostname: row.get("hostname"),
+            username: row.get("username"),
+            shell: row.get("shell"),
+            tty: row.get("tty"),
+            start_time: Utc.timestamp_nanos(row.get("start_time")),
+            end_time: row
+                .get::<Option<i64>, _>("end_time")
+                .map(|t| Utc.timestamp_nanos(t)),
         }
     }
 }
 
 #[async_trait]
 impl Database for Sqlite {
-    async fn save(&mut self, h: &HistoryItem) -> Result<(), sqlx::Error> {
+    async fn save(&mut self, h: &HistoryItem) -> Result<Option<i64>, sqlx::Error> {
         debug!("saving history to sqlite");
 
+        let h = match self.filter(h) {
+            Some(h) => h,
+            None => {
+                debug!("not saving filtered command");
+                return Ok(None);
+            }
+        };
+
         let mut tx = self.pool.begin().await?;
-        Self::save_raw(&mut tx, h).await?;
+        if self.ignore.dups && Self::is_dup(&mut tx, &h).await? {
+            debug!("not saving duplicate command");
+            return Ok(None);
+        }
+        let id = Self::save_raw(&mut tx, &h).await?;
         tx.commit().await?;
 
-        Ok(())
+        Ok(Some(id))
     }
 
     async fn save_bulk(&mut self, h: &[HistoryItem]) -> Result<(), sqlx::Error> {
@@ -182,8 +704,45 @@ impl Database for Sqlite {
 
         let mut tx = self.pool.begin().await?;
 
-        for i in h {
-            Self::save_raw(&mut tx, i).await?
+        for i in h.iter().filter_map(|i| self.filter(i)) {
+            if self.ignore.dups && Self::is_dup(&mut tx, &i).await? {
+                continue;
+            }
+            Self::save_raw(&mut tx, &i).await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn save_pulled(&mut self, h: &[HistoryItem], host: &str) -> Result<(), sqlx::Error> {
+        debug!("saving {} commands pulled from {}", h.len(), host);
+
+        let mut tx = self.pool.begin().await?;
+        // new rows get ids past this one. One that was already here (our own
+        // command coming back) isn't marked, it may still have to go out
+        // again once it has finished.
+        let (newest,): (i64,) =
+            sqlx::query_as("select coalesce(max(history_id), 0) from history_items")
+                .fetch_one(&mut tx)
+                .await?;
+
+        for i in h.iter().filter_map(|i| self.filter(i)) {
+            if self.ignore.dups && Self::is_dup(&mut tx, &i).await? {
+                continue;
+            }
+            let id = Self::save_raw(&mut tx, &i).await?;
+            if id > newest {
+                sqlx::query(
+                    "insert into sync_pulled(history_id, host) values(?1, ?2)
+                        on conflict do nothing",
+                )
+                .bind(id)
+                .bind(host)
+                .execute(&mut tx)
+                .await?;
+            }
         }
 
         tx.commit().await?;
@@ -191,6 +750,24 @@ impl Database for Sqlite {
         Ok(())
     }
 
+    async fn pulled_ids(
+        &self,
+        host: &str,
+        after: i64,
+        up_to: i64,
+    ) -> Result<HashSet<i64>, sqlx::Error> {
+        let res: Vec<(i64,)> = sqlx::query_as(
+            "select history_id from sync_pulled where host = ?1 and history_id > ?2 and history_id <= ?3",
+        )
+        .bind(host)
+        .bind(after)
+        .bind(up_to)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(res.into_iter().map(|(id,)| id).collect())
+    }
+
     async fn load(&self, id: &str) -> Result<HistoryItem, sqlx::Error> {
         debug!("loading history item {}", id);
 
@@ -204,62 +781,64 @@ impl Database for Sqlite {
     }
 
     async fn update(&self, h: &HistoryItem) -> Result<(), sqlx::Error> {
-        debug!("updating sqlite history");
-        debug!("history_item = [{:?}]", &h);
+        debug!("updating history item {:?}", h.history_id);
+
+        // a changed command goes through the same rules as a new one, but
+        // there's no quietly dropping a row somebody asked to change
+        if self.ignore.ignores(&h.command) {
+            return Err(sqlx::Error::Configuration(
+                "the ignore rules skip that command, nothing was updated".into(),
+            ));
+        }
+        let command = match self.scrubber.scrub(&h.command) {
+            Scrubbed::Clean => Cow::Borrowed(&h.command),
+            Scrubbed::Redacted(command) => Cow::Owned(command),
+            Scrubbed::Drop => {
+                return Err(sqlx::Error::Configuration(
+                    "the scrub rules drop that command, nothing was updated".into(),
+                ))
+            }
+        };
 
         sqlx::query(
             "update history_items
-                set timestamp = ?2, duration = ?3, exit_status = ?4, command = ?5, cwd = ?6, session_id = ?7
+                set timestamp = ?2, duration = ?3, exit_status = ?4, command = ?5, cwd = ?6, session_id = ?7,
+                    hostname = ?8, username = ?9
                 where history_id = ?1",
         )
         .bind(h.history_id)
         .bind(h.timestamp.timestamp_nanos())
         .bind(h.duration)
         .bind(h.exit_status)
-        .bind(h.command.as_str())
+        .bind(command.as_str())
         .bind(h.cwd.as_str())
         .bind(h.session_id)
+        .bind(h.hostname.as_str())
+        .bind(h.username.as_str())
         .execute(&self.pool)
         .await?;
 
         Ok(())
     }
 
-    // make a unique list, that only shows the *newest* version of things
-    async fn list(
-        &self,
-        max: Option<usize>,
-        unique: bool,
-    ) -> Result<Vec<HistoryItem>, sqlx::Error> {
-        debug!("listing history");
-
-        // very likely vulnerable to SQL injection
-        // however, this is client side, and only used by the client, on their
-        // own data. They can just open the db file...
-        // otherwise building the query is awkward
-        let query = format!(
-            "select * from history_items h
-                {}
-                order by timestamp desc
-                {}",
-            // inject the unique check
-            if unique {
-                "where timestamp = (
-                        select max(timestamp) from history_items
-                        where h.command = history_items.command
-                    )"
+    async fn query(&self, filter: &HistoryFilter) -> Result<Vec<HistoryItem>, sqlx::Error> {
+        debug!("querying history with {:?}", filter);
+
+        let mut qb = QueryBuilder::new("select * from history_items h where 1");
+        filter.push_conditions(&mut qb, "h");
+        filter.push_order(
+            &mut qb,
+            "h",
+            if filter.oldest_first {
+                "h.timestamp asc"
             } else {
-                ""
+                "h.timestamp desc"
             },
-            // inject the limit
-            if let Some(max) = max {
-                format!("limit {}", max)
-            } else {
-                "".to_string()
-            }
         );
+        filter.push_paging(&mut qb);
 
-        let res = sqlx::query(query.as_str())
+        let res = qb
+            .build()
             .map(Self::query_history)
             .fetch_all(&self.pool)
             .await?;
@@ -267,25 +846,6 @@ impl Database for Sqlite {
         Ok(res)
     }
 
-    async fn range(
-        &self,
-        from: chrono::DateTime<Utc>,
-        to: chrono::DateTime<Utc>,
-    ) -> Result<Vec<HistoryItem>, sqlx::Error> {
-        debug!("listing history from {:?} to {:?}", from, to);
-
-        let res = sqlx::query(
-            "select * from history_items where timestamp >= ?1 and timestamp <= ?2 order by timestamp asc",
-        )
-        .bind(from.timestamp_nanos())
-        .bind(to.timestamp_nanos())
-            .map(Self::query_history)
-        .fetch_all(&self.pool)
-        .await?;
-
-        Ok(res)
-    }
-
     async fn first(&self) -> Result<HistoryItem, sqlx::Error> {
         let res = sqlx::query(
             "select * from history_items where duration >= 0 order by timestamp asc limit 1",
@@ -335,36 +895,60 @@ impl Database for Sqlite {
 
     async fn search(
         &self,
-        limit: Option<i64>,
         search_mode: SearchMode,
         query: &str,
+        filter: &HistoryFilter,
     ) -> Result<Vec<HistoryItem>, sqlx::Error> {
-        let query = query.to_string().replace("*", "%"); // allow wildcard char
-        let limit = limit.map_or("".to_owned(), |l| format!("limit {}", l));
+        let filter = &HistoryFilter {
+            unique: true,
+            ..filter.clone()
+        };
+
+        self.matches(search_mode, query, filter).await
+    }
 
-        let query = match search_mode {
-            SearchMode::Prefix => query,
-            SearchMode::FullText => format!("%{}", query),
-            SearchMode::Fuzzy => query.split("").join("%"),
+    async fn fuzzy_search(
+        &self,
+        query: &str,
+        filter: &HistoryFilter,
+    ) -> Result<Vec<FuzzyMatch>, sqlx::Error> {
+        let filter = &HistoryFilter {
+            unique: true,
+            ..filter.clone()
         };
 
-        let res = sqlx::query(
-            format!(
-                "select * from history_items h
-                where command like ?1 || '%'
-                and timestamp = (
-                        select max(timestamp) from history_items
-                        where h.command = history_items.command
-                    )
-                order by timestamp desc {}",
-                limit.clone()
-            )
-            .as_str(),
-        )
-        .bind(query)
-        .map(Self::query_history)
-        .fetch_all(&self.pool)
-        .await?;
+        self.fuzzy_matches(query, filter).await
+    }
+
+    async fn matches(
+        &self,
+        search_mode: SearchMode,
+        query: &str,
+        filter: &HistoryFilter,
+    ) -> Result<Vec<HistoryItem>, sqlx::Error> {
+        match search_mode {
+            SearchMode::FullText => return self.search_fulltext(query, filter).await,
+            SearchMode::Fuzzy => {
+                let res = self.fuzzy_matches(query, filter).await?;
+                return Ok(res.into_iter().map(|m| m.item).collect());
+            }
+            SearchMode::Regex => return self.search_regex(query, filter).await,
+            SearchMode::Prefix => {}
+        }
+
+        let query = query.to_string().replace("*", "%"); // allow wildcard char
+
+        let mut qb = QueryBuilder::new("select * from history_items h where h.command like ");
+        qb.push_bind(query).push(" || '%'");
+        filter.push_conditions(&mut qb, "h");
+        filter.push_order(&mut qb, "h", "h.timestamp desc");
+        filter.push_paging(&mut qb);
+
+        let res = qb
+            .build()
+            .map(Self::query_history)
+            .fetch_all(&self.pool)
+            .await?;
 
         Ok(res)
     }
@@ -386,102 +970,1676 @@ impl Database for Sqlite {
             .rows_affected();
         Ok(res)
     }
-}
 
-#[derive(Clone, Debug, Copy)]
-pub enum SearchMode {
-    // #[serde(rename = "prefix")]
-    Prefix,
+    async fn delete_history_items(&self, ids: &[i64]) -> Result<u64, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let mut deleted = 0;
+
+        for id in ids {
+            deleted += sqlx::query("delete from history_items where history_id = ?1")
+                .bind(id)
+                .execute(&mut tx)
+                .await?
+                .rows_affected();
+        }
+        tx.commit().await?;
 
-    // #[serde(rename = "fulltext")]
-    FullText,
+        Ok(deleted)
+    }
 
-    // #[serde(rename = "fuzzy")]
-    Fuzzy,
-}
+    async fn after(&self, history_id: i64, limit: i64) -> Result<Vec<HistoryItem>, sqlx::Error> {
+        debug!("listing {} history items after id {}", limit, history_id);
 
-#[cfg(test)]
-mod test {
-    use super::*;
+        let res = sqlx::query(
+            "select * from history_items where history_id > ?1 order by history_id asc limit ?2",
+        )
+        .bind(history_id)
+        .bind(limit)
+        .map(Self::query_history)
+        .fetch_all(&self.pool)
+        .await?;
 
-    async fn new_history_item(db: &mut impl Database, cmd: &str) -> Result<()> {
-        let history = HistoryItem::new(
-            chrono::Local::now(),
-            cmd.to_string(),
-            "/home/ellie".to_string(),
-            0,
-            1,
-            Some("beep boop".to_string()),
-            Some("booop".to_string()),
-        );
-        return db.save(&history).await;
+        Ok(res)
     }
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn test_search_prefix() {
-        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
-        new_history_item(&mut db, "ls /home/ellie").await.unwrap();
-
-        let mut results = db.search(None, SearchMode::Prefix, "ls").await.unwrap();
-        assert_eq!(results.len(), 1);
+    async fn server_id(&self) -> Result<String, sqlx::Error> {
+        // two clients asking at once still end up with the same one
+        sqlx::query("insert into sync_server(id, server_id) values(1, ?1) on conflict do nothing")
+            .bind(session::new_uuid())
+            .execute(&self.pool)
+            .await?;
 
-        results = db.search(None, SearchMode::Prefix, "/home").await.unwrap();
-        assert_eq!(results.len(), 0);
+        let res: (String,) = sqlx::query_as("select server_id from sync_server")
+            .fetch_one(&self.pool)
+            .await?;
 
-        results = db.search(None, SearchMode::Prefix, "ls  ").await.unwrap();
-        assert_eq!(results.len(), 0);
+        Ok(res.0)
     }
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn test_search_fulltext() {
-        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
-        new_history_item(&mut db, "ls /home/ellie").await.unwrap();
+    async fn rename_sync_host(&self, from: &str, to: &str) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
 
-        let mut results = db.search(None, SearchMode::FullText, "ls").await.unwrap();
-        assert_eq!(results.len(), 1);
+        let (known,): (bool,) =
+            sqlx::query_as("select exists(select 1 from sync_state where host = ?1)")
+                .bind(to)
+                .fetch_one(&mut tx)
+                .await?;
+        if !known {
+            debug!("moving the sync state for {} over to {}", from, to);
+            sqlx::query("update sync_state set host = ?2 where host = ?1")
+                .bind(from)
+                .bind(to)
+                .execute(&mut tx)
+                .await?;
+            sqlx::query("update or ignore sync_pulled set host = ?2 where host = ?1")
+                .bind(from)
+                .bind(to)
+                .execute(&mut tx)
+                .await?;
+            sqlx::query("delete from sync_pulled where host = ?1")
+                .bind(from)
+                .execute(&mut tx)
+                .await?;
+        }
 
-        results = db
-            .search(None, SearchMode::FullText, "/home")
-            .await
-            .unwrap();
-        assert_eq!(results.len(), 1);
+        tx.commit().await?;
 
-        results = db.search(None, SearchMode::FullText, "ls  ").await.unwrap();
-        assert_eq!(results.len(), 0);
+        Ok(())
     }
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn test_search_fuzzy() {
-        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
-        new_history_item(&mut db, "ls /home/ellie").await.unwrap();
+    async fn sync_state(&self, host: &str) -> Result<(i64, i64), sqlx::Error> {
+        let res: Option<(i64, i64)> =
+            sqlx::query_as("select last_pushed, last_pulled from sync_state where host = ?1")
+                .bind(host)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(res.unwrap_or((0, 0)))
+    }
+
+    async fn set_sync_state(
+        &self,
+        host: &str,
+        last_pushed: i64,
+        last_pulled: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "insert into sync_state(host, last_pushed, last_pulled, synced_at) values(?1, ?2, ?3, ?4)
+                on conflict(host) do update set
+                    last_pushed = excluded.last_pushed,
+                    last_pulled = excluded.last_pulled,
+                    synced_at = excluded.synced_at",
+        )
+        .bind(host)
+        .bind(last_pushed)
+        .bind(last_pulled)
+        .bind(Utc::now().timestamp_nanos())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Returns how many records were new or replaced an older version. A
+    // replaced record gets a new id so clients that already pulled the old
+    // one pull it again.
+    async fn save_records(&mut self, records: &[EncryptedItem]) -> Result<usize, sqlx::Error> {
+        debug!("saving {} sync records", records.len());
+
+        let mut tx = self.pool.begin().await?;
+        let mut saved = 0;
+
+        for r in records {
+            let existing: Option<(i64,)> =
+                sqlx::query_as("select version from sync_records where record_key = ?1")
+                    .bind(r.record_key.as_str())
+                    .fetch_optional(&mut tx)
+                    .await?;
+
+            match existing {
+                Some((version,)) if version >= r.version => continue,
+                Some(_) => {
+                    sqlx::query("delete from sync_records where record_key = ?1")
+                        .bind(r.record_key.as_str())
+                        .execute(&mut tx)
+                        .await?;
+                }
+                None => {}
+            }
+
+            sqlx::query(
+                "insert into sync_records(record_key, version, nonce, data) values(?1, ?2, ?3, ?4)",
+            )
+            .bind(r.record_key.as_str())
+            .bind(r.version)
+            .bind(r.nonce.as_str())
+            .bind(r.data.as_str())
+            .execute(&mut tx)
+            .await?;
+            saved += 1;
+        }
+
+        tx.commit().await?;
+
+        Ok(saved)
+    }
+
+    async fn scrub(&self, dry_run: bool) -> Result<(u64, u64), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let rows: Vec<(i64, i64, String, String)> =
+            sqlx::query_as("select history_id, timestamp, cwd, command from history_items")
+                .fetch_all(&mut tx)
+                .await?;
+
+        let (mut redacted, mut dropped) = (0, 0);
+        for (id, timestamp, cwd, command) in rows {
+            let delete = match self.scrubber.scrub(&command) {
+                Scrubbed::Clean => continue,
+                Scrubbed::Drop => {
+                    dropped += 1;
+                    true
+                }
+                Scrubbed::Redacted(scrubbed) => {
+                    redacted += 1;
+                    // two commands that only differed in the secret end up
+                    // the same, keep the one that's already redacted
+                    let twin: Option<(i64,)> = sqlx::query_as(
+                        "select history_id from history_items where timestamp = ?1 and cwd = ?2 and command = ?3",
+                    )
+                    .bind(timestamp)
+                    .bind(cwd.as_str())
+                    .bind(scrubbed.as_str())
+                    .fetch_optional(&mut tx)
+                    .await?;
+
+                    if twin.is_none() {
+                        sqlx::query("update history_items set command = ?1 where history_id = ?2")
+                            .bind(scrubbed.as_str())
+                            .bind(id)
+                            .execute(&mut tx)
+                            .await?;
+                    }
+                    twin.is_some()
+                }
+            };
+
+            if delete {
+                sqlx::query("delete from history_items where history_id = ?1")
+                    .bind(id)
+                    .execute(&mut tx)
+                    .await?;
+            }
+        }
+
+        if dry_run {
+            tx.rollback().await?;
+        } else {
+            tx.commit().await?;
+        }
+
+        Ok((redacted, dropped))
+    }
+
+    async fn check(&self, dry_run: bool) -> Result<Vec<Problem>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let mut problems = vec![];
+
+        // every declared foreign key, so a new child table is covered as soon
+        // as it references its parent
+        let violations: Vec<(String, Option<i64>, String)> =
+            sqlx::query("pragma foreign_key_check")
+                .map(|row: SqliteRow| (row.get("table"), row.get("rowid"), row.get("parent")))
+                .fetch_all(&mut tx)
+                .await?;
+        let mut by_table: BTreeMap<(String, String), Vec<i64>> = BTreeMap::new();
+        for (table, id, parent) in violations {
+            by_table.entry((table, parent)).or_default().extend(id);
+        }
+        for ((table, parent), ids) in by_table {
+            for id in &ids {
+                sqlx::query(&format!("delete from {} where rowid = ?1", table))
+                    .bind(id)
+                    .execute(&mut tx)
+                    .await?;
+            }
+            problems.push(Problem {
+                table,
+                problem: format!("rows whose {} row is gone", parent),
+                rows: ids.len() as i64,
+                repair: "deleted".to_string(),
+                repaired: !dry_run,
+            });
+        }
+
+        // history_items.session_id has no foreign key, the session rows
+        // came later, so a missing one is made up the way save_raw does it
+        let orphans: Vec<(i64, i64, i64, i64)> = sqlx::query_as(
+            "select session_id, count(*), min(timestamp), max(timestamp) from history_items h
+                where not exists (select 1 from sessions s where s.session_id = h.session_id)
+                group by session_id",
+        )
+        .fetch_all(&mut tx)
+        .await?;
+        if !orphans.is_empty() {
+            for (session_id, _, start_time, end_time) in &orphans {
+                sqlx::query(
+                    "insert into sessions(session_id, uuid, start_time, end_time) values(?1, ?2, ?3, ?4)",
+                )
+                .bind(session_id)
+                .bind(session::new_uuid())
+                .bind(start_time)
+                .bind(end_time)
+                .execute(&mut tx)
+                .await?;
+            }
+            problems.push(Problem {
+                table: "history_items".to_string(),
+                problem: "rows whose sessions row is gone".to_string(),
+                rows: orphans.iter().map(|(_, count, _, _)| count).sum(),
+                repair: format!("recreated {} sessions", orphans.len()),
+                repaired: !dry_run,
+            });
+        }
+
+        // history_fts only has the index, its docsize table has one row per
+        // indexed command to hold it against
+        let (stale, missing): (i64, i64) = sqlx::query_as(
+            "select
+                (select count(*) from history_fts_docsize d
+                    where not exists (select 1 from history_items h where h.history_id = d.id)),
+                (select count(*) from history_items h
+                    where not exists (select 1 from history_fts_docsize d where d.id = h.history_id))",
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        if stale + missing > 0 {
+            sqlx::query("insert into history_fts(history_fts) values('rebuild')")
+                .execute(&mut tx)
+                .await?;
+            problems.push(Problem {
+                table: "history_fts".to_string(),
+                problem: format!("{} stale and {} missing index entries", stale, missing),
+                rows: stale + missing,
+                repair: "rebuilt".to_string(),
+                repaired: !dry_run,
+            });
+        }
+
+        if dry_run {
+            tx.rollback().await?;
+        } else {
+            tx.commit().await?;
+        }
+
+        Ok(problems)
+    }
+
+    async fn prune(
+        &self,
+        retention: &RetentionSettings,
+        dry_run: bool,
+    ) -> Result<Vec<Pruned>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let mut pruned = vec![];
+
+        for (rule, filter) in retention.rules(Utc::now()) {
+            let rows = match filter {
+                Rule::Drop(filter) => Self::delete_where(&mut tx, &filter, false).await?,
+                Rule::Keep(filter) => Self::delete_where(&mut tx, &filter, true).await?,
+            };
+            debug!("pruning {} dropped {} commands", rule, rows);
+            pruned.push(Pruned { rule, rows });
+        }
+
+        if dry_run {
+            tx.rollback().await?;
+        } else {
+            tx.commit().await?;
+        }
+
+        Ok(pruned)
+    }
+
+    async fn compact(&self) -> Result<(), sqlx::Error> {
+        // VACUUM goes through the wal like everything else, the checkpoint
+        // after it is what gives the space back
+        sqlx::query("vacuum").execute(&self.pool).await?;
+        sqlx::query("pragma wal_checkpoint(truncate)")
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn records_after(
+        &self,
+        record_id: i64,
+        limit: i64,
+    ) -> Result<Vec<EncryptedItem>, sqlx::Error> {
+        let res = sqlx::query(
+            "select * from sync_records where record_id > ?1 order by record_id asc limit ?2",
+        )
+        .bind(record_id)
+        .bind(limit)
+        .map(|row: SqliteRow| EncryptedItem {
+            record_id: Some(row.get("record_id")),
+            record_key: row.get("record_key"),
+            version: row.get("version"),
+            nonce: row.get("nonce"),
+            data: row.get("data"),
+        })
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(res)
+    }
+
+    async fn save_session(&mut self, s: &Session) -> Result<i64, sqlx::Error> {
+        debug!("saving session {}", s.uuid);
+
+        // a session that comes back (from a sync) only ever learns when it
+        // ended, the rest was known from the start. fetch_all and not
+        // fetch_one, that would leave the statement and so its write
+        // transaction open and the next writer gets "database is locked".
+        let res: Vec<(i64,)> = sqlx::query_as(
+            "insert into sessions(uuid, hostname, username, shell, tty, start_time, end_time)
+                values(?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                on conflict(uuid) do update set end_time = coalesce(sessions.end_time, excluded.end_time)
+                returning session_id",
+        )
+        .bind(s.uuid.as_str())
+        .bind(s.hostname.as_str())
+        .bind(s.username.as_str())
+        .bind(s.shell.as_str())
+        .bind(s.tty.as_str())
+        .bind(s.start_time.timestamp_nanos())
+        .bind(s.end_time.map(|t| t.timestamp_nanos()))
+        .fetch_all(&self.pool)
+        .await?;
+
+        res.first().map(|r| r.0).ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn end_session(
+        &self,
+        session_id: i64,
+        end_time: chrono::DateTime<Utc>,
+    ) -> Result<u64, sqlx::Error> {
+        let res = sqlx::query("update sessions set end_time = ?1 where session_id = ?2")
+            .bind(end_time.timestamp_nanos())
+            .bind(session_id)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+
+        Ok(res)
+    }
+
+    async fn session(&self, key: &str) -> Result<Option<Session>, sqlx::Error> {
+        let query = match key.parse::<i64>() {
+            Ok(id) => sqlx::query("select * from sessions where session_id = ?1").bind(id),
+            Err(_) => sqlx::query("select * from sessions where uuid = ?1").bind(key),
+        };
+
+        let res = query
+            .map(Self::query_session)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(res)
+    }
+
+    async fn sessions(&self, limit: Option<i64>) -> Result<Vec<Session>, sqlx::Error> {
+        // the unknown session is a bucket, not a terminal anybody opened
+        let res = sqlx::query(
+            "select * from sessions where session_id != ?1
+                order by start_time desc, session_id desc limit ?2",
+        )
+        .bind(session::UNKNOWN_SESSION_ID)
+        .bind(limit.unwrap_or(-1))
+        .map(Self::query_session)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(res)
+    }
+
+    async fn stats(
+        &self,
+        filter: &HistoryFilter,
+        top: i64,
+        min_runs: i64,
+    ) -> Result<Stats, sqlx::Error> {
+        let mut qb = QueryBuilder::new("select count(*) from history_items h where 1");
+        filter.push_conditions(&mut qb, "h");
+        let total = qb.build().fetch_one(&self.pool).await?.get(0);
+
+        // the first word, after leading whitespace
+        let binary = "case when instr(trim(h.command), ' ') > 0
+            then substr(trim(h.command), 1, instr(trim(h.command), ' ') - 1)
+            else trim(h.command) end";
+
+        Ok(Stats {
+            total,
+            commands: self.top(filter, "h.command", top).await?,
+            binaries: self.top(filter, binary, top).await?,
+            directories: self.top(filter, "h.cwd", top).await?,
+            heatmap: self.heatmap(filter).await?,
+            slowest: self
+                .command_stats(
+                    filter,
+                    "p95_duration >= 0",
+                    "p95_duration desc, r.runs desc",
+                    min_runs,
+                    top,
+                )
+                .await?,
+            flakiest: self
+                .command_stats(
+                    filter,
+                    "r.failed > 0",
+                    "failure_rate desc, r.failed desc",
+                    min_runs,
+                    top,
+                )
+                .await?,
+        })
+    }
+
+    async fn save_perf(&self, perf: &Perf) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "insert into performance_items(history_id, wall_time, user_time, sys_time, max_rss)
+                values(?1, ?2, ?3, ?4, ?5)
+                on conflict(history_id) do update set
+                    wall_time = excluded.wall_time,
+                    user_time = excluded.user_time,
+                    sys_time = excluded.sys_time,
+                    max_rss = excluded.max_rss",
+        )
+        .bind(perf.history_id)
+        .bind(perf.wall_time)
+        .bind(perf.user_time)
+        .bind(perf.sys_time)
+        .bind(perf.max_rss)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn perf(
+        &self,
+        command: &str,
+        by: Period,
+        filter: &HistoryFilter,
+    ) -> Result<Vec<PerfSummary>, sqlx::Error> {
+        let mut qb = QueryBuilder::new(format!(
+            "select {} as period, count(*) as runs,
+                cast(avg(p.wall_time) as integer) as wall_time,
+                cast(avg(p.user_time) as integer) as user_time,
+                cast(avg(p.sys_time) as integer) as sys_time,
+                max(p.max_rss) as max_rss
+            from performance_items p
+                join history_items h on h.history_id = p.history_id
+            where h.command = ",
+            by.sql("h")
+        ));
+        qb.push_bind(command);
+        filter.push_conditions(&mut qb, "h");
+        qb.push(if by == Period::Run {
+            " group by h.history_id"
+        } else {
+            " group by period"
+        });
+        qb.push(" order by min(h.timestamp) asc");
+
+        let res = qb
+            .build()
+            .map(|row: SqliteRow| PerfSummary {
+                period: row.get("period"),
+                runs: row.get("runs"),
+                wall_time: row.get("wall_time"),
+                user_time: row.get("user_time"),
+                sys_time: row.get("sys_time"),
+                max_rss: row.get("max_rss"),
+                change: None,
+                regression: false,
+            })
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(res)
+    }
+}
+
+#[derive(Clone, Debug, Copy, PartialEq)]
+pub enum SearchMode {
+    // #[serde(rename = "prefix")]
+    Prefix,
+
+    // #[serde(rename = "fulltext")]
+    FullText,
+
+    // #[serde(rename = "fuzzy")]
+    Fuzzy,
+
+    // #[serde(rename = "regex")]
+    Regex,
+}
+
+impl SearchMode {
+    // the short names --mode takes
+    pub const NAMES: &'static [&'static str] = &["p", "f", "z", "r"];
+}
+
+impl FromStr for SearchMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "p" => Ok(SearchMode::Prefix),
+            "f" => Ok(SearchMode::FullText),
+            "z" => Ok(SearchMode::Fuzzy),
+            "r" => Ok(SearchMode::Regex),
+            _ => Err(format!(
+                "unsupported search mode {}, expected one of {}",
+                s,
+                SearchMode::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for SearchMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            SearchMode::Prefix => "prefix",
+            SearchMode::FullText => "fulltext",
+            SearchMode::Fuzzy => "fuzzy",
+            SearchMode::Regex => "regex",
+        };
+        f.write_str(name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::filter::{CwdFilter, ExitFilter};
+    use crate::ignore::IgnoreSettings;
+    use crate::scrub::{ScrubAction, ScrubSettings};
+
+    async fn new_history_item(
+        db: &mut impl Database,
+        cmd: &str,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        let history = HistoryItem::new(
+            None,
+            cmd.to_string(),
+            "/home/ellie".to_string(),
+            0,
+            1,
+            Some(1),
+            Utc::now(),
+        );
+        db.save(&history).await
+    }
+
+    #[async_std::test]
+    async fn test_save_returns_history_id() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        let first = new_history_item(&mut db, "ls").await.unwrap().unwrap();
+        let second = new_history_item(&mut db, "pwd").await.unwrap().unwrap();
+        assert_ne!(first, second);
+
+        let mut item = db.load(&second.to_string()).await.unwrap();
+        assert_eq!(item.command, "pwd");
+
+        // saving the same row again is ignored and gives back the same id
+        item.history_id = None;
+        assert_eq!(db.save(&item).await.unwrap(), Some(second));
+        assert_eq!(db.history_count().await.unwrap(), 2);
+
+        // a copy saved while it was still running catches up when it's done
+        item.command = "make".to_string();
+        item.duration = -1;
+        item.exit_status = -1;
+        let running = db.save(&item).await.unwrap();
+        item.duration = 5;
+        item.exit_status = 2;
+        assert_eq!(db.save(&item).await.unwrap(), running);
+        let done = db.load(&running.unwrap().to_string()).await.unwrap();
+        assert_eq!((done.duration, done.exit_status), (5, 2));
+    }
+
+    #[async_std::test]
+    async fn test_save_ignores_given_history_id() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        new_history_item(&mut db, "ls").await.unwrap().unwrap();
+        let pushed = new_history_item(&mut db, "pwd").await.unwrap().unwrap();
+
+        // an imported item with a low id still lands after everything sync
+        // has already pushed
+        let item = HistoryItem::new(
+            Some(1),
+            "cargo build".to_string(),
+            "/home/ellie".to_string(),
+            0,
+            0,
+            Some(1),
+            Utc::now(),
+        );
+        let id = db.save(&item).await.unwrap().unwrap();
+        assert_eq!(id, pushed + 1);
+        let after = db.after(pushed, 10).await.unwrap();
+        assert_eq!(after.len(), 1);
+        assert_eq!(after[0].command, "cargo build");
+    }
+
+    #[async_std::test]
+    async fn test_save_scrubs_secrets() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        let id = new_history_item(&mut db, "PASSWORD=hunter2 ./deploy")
+            .await
+            .unwrap()
+            .unwrap();
+        let item = db.load(&id.to_string()).await.unwrap();
+        assert_eq!(item.command, "PASSWORD=[REDACTED] ./deploy");
+        assert_eq!(
+            db.search(SearchMode::FullText, "hunter2", &HistoryFilter::default())
+                .await
+                .unwrap()
+                .len(),
+            0
+        );
+
+        let mut db = db.with_scrubber(Scrubber::new(&ScrubSettings {
+            action: ScrubAction::Drop,
+            ..ScrubSettings::default()
+        }));
+        assert_eq!(
+            new_history_item(&mut db, "PASSWORD=hunter2 ./deploy")
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[async_std::test]
+    async fn test_update_scrubs_secrets() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        let id = new_history_item(&mut db, "./deploy")
+            .await
+            .unwrap()
+            .unwrap();
+        let mut item = db.load(&id.to_string()).await.unwrap();
+
+        item.command = "PASSWORD=hunter2 ./deploy".to_string();
+        db.update(&item).await.unwrap();
+        let saved = db.load(&id.to_string()).await.unwrap();
+        assert_eq!(saved.command, "PASSWORD=[REDACTED] ./deploy");
+
+        // what save would drop isn't written at all
+        let db = db
+            .with_scrubber(Scrubber::new(&ScrubSettings {
+                action: ScrubAction::Drop,
+                ..ScrubSettings::default()
+            }))
+            .with_ignore_rules(IgnoreRules::new(&IgnoreSettings {
+                patterns: vec!["cd *".to_string()],
+                ..IgnoreSettings::default()
+            }));
+        assert!(db.update(&item).await.is_err());
+        item.command = "cd /tmp".to_string();
+        assert!(db.update(&item).await.is_err());
+        let saved = db.load(&id.to_string()).await.unwrap();
+        assert_eq!(saved.command, "PASSWORD=[REDACTED] ./deploy");
+    }
+
+    #[async_std::test]
+    async fn test_save_applies_ignore_rules() {
+        let mut db = Sqlite::new("sqlite::memory:")
+            .await
+            .unwrap()
+            .with_ignore_rules(IgnoreRules::new(&IgnoreSettings {
+                patterns: vec!["cd *".to_string()],
+                ..IgnoreSettings::default()
+            }));
+
+        assert!(new_history_item(&mut db, "ls").await.unwrap().is_some());
+        assert_eq!(new_history_item(&mut db, "ls").await.unwrap(), None);
+        assert_eq!(new_history_item(&mut db, " ls -la").await.unwrap(), None);
+        assert_eq!(new_history_item(&mut db, "cd /tmp").await.unwrap(), None);
+        assert!(new_history_item(&mut db, "pwd").await.unwrap().is_some());
+        // only the previous command counts as a duplicate
+        assert!(new_history_item(&mut db, "ls").await.unwrap().is_some());
+
+        let now = Utc::now();
+        let bulk: Vec<HistoryItem> = ["make", "make", "cd src", "make test"]
+            .iter()
+            .enumerate()
+            .map(|(idx, cmd)| {
+                HistoryItem::new(
+                    None,
+                    cmd.to_string(),
+                    "/home/ellie".to_string(),
+                    0,
+                    0,
+                    Some(2),
+                    now + chrono::Duration::seconds(idx as i64),
+                )
+            })
+            .collect();
+        db.save_bulk(&bulk).await.unwrap();
+
+        let saved: Vec<String> = db
+            .query(&HistoryFilter::default())
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|i| i.session_id == 2)
+            .map(|i| i.command)
+            .collect();
+        assert_eq!(saved, vec!["make test", "make"]);
+    }
+
+    #[async_std::test]
+    async fn test_scrub_existing_history() {
+        let mut db = Sqlite::new("sqlite::memory:")
+            .await
+            .unwrap()
+            .with_scrubber(Scrubber::new(&ScrubSettings {
+                builtin: false,
+                ..ScrubSettings::default()
+            }));
+        new_history_item(&mut db, "PASSWORD=hunter2 ./deploy")
+            .await
+            .unwrap();
+        new_history_item(&mut db, "ls").await.unwrap();
+
+        let db = db.with_scrubber(Scrubber::default());
+        assert_eq!(db.scrub(true).await.unwrap(), (1, 0));
+        assert_eq!(
+            db.search(
+                SearchMode::Prefix,
+                "PASSWORD=hunter2",
+                &HistoryFilter::default()
+            )
+            .await
+            .unwrap()
+            .len(),
+            1
+        );
+
+        assert_eq!(db.scrub(false).await.unwrap(), (1, 0));
+        assert_eq!(
+            db.search(
+                SearchMode::Prefix,
+                "PASSWORD=[REDACTED]",
+                &HistoryFilter::default()
+            )
+            .await
+            .unwrap()
+            .len(),
+            1
+        );
+        assert_eq!(db.scrub(false).await.unwrap(), (0, 0));
+    }
+
+    #[async_std::test]
+    async fn test_sessions() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        // a session_id nobody started gets a session row of its own
+        new_history_item(&mut db, "ls").await.unwrap();
+
+        let session = Session::new("bash", "/dev/pts/1");
+        let id = db.save_session(&session).await.unwrap();
+        assert_eq!(db.save_session(&session).await.unwrap(), id);
+
+        let now = Utc::now();
+        for (idx, cmd) in ["cd hiztery", "cargo build"].iter().enumerate() {
+            let item = HistoryItem::new(
+                None,
+                cmd.to_string(),
+                "/home/ellie".to_string(),
+                0,
+                0,
+                Some(id),
+                now + chrono::Duration::seconds(idx as i64),
+            );
+            db.save(&item).await.unwrap();
+        }
+
+        let found = db.session(&session.uuid).await.unwrap().unwrap();
+        assert_eq!(found.session_id, Some(id));
+        assert_eq!(found.shell, "bash");
+        assert_eq!(db.session(&id.to_string()).await.unwrap(), Some(found));
+        assert_eq!(db.session("nope").await.unwrap(), None);
+
+        let history: Vec<String> = db
+            .query(&HistoryFilter {
+                session_id: Some(id),
+                oldest_first: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|i| i.command)
+            .collect();
+        assert_eq!(history, vec!["cd hiztery", "cargo build"]);
+
+        assert_eq!(db.end_session(id, now).await.unwrap(), 1);
+        let sessions = db.sessions(None).await.unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions.iter().any(|s| s.session_id == Some(1)));
+        let ended = sessions.iter().find(|s| s.session_id == Some(id)).unwrap();
+        assert_eq!(ended.end_time, Some(now));
+    }
+
+    #[async_std::test]
+    async fn test_origin_filters() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        let id = new_history_item(&mut db, "ls").await.unwrap().unwrap();
+        let item = db.load(&id.to_string()).await.unwrap();
+        assert_eq!(item.hostname, session::hostname());
+        assert_eq!(item.username, session::username());
+
+        let now = Utc::now();
+        for (idx, (cmd, host)) in [("make", "laptop"), ("make", "desktop"), ("ls", "desktop")]
+            .iter()
+            .enumerate()
+        {
+            let mut item = HistoryItem::new(
+                None,
+                cmd.to_string(),
+                "/home/ellie".to_string(),
+                0,
+                0,
+                Some(idx as i64 + 2),
+                now + chrono::Duration::seconds(idx as i64 + 1),
+            );
+            item.hostname = host.to_string();
+            item.username = "ellie".to_string();
+            db.save(&item).await.unwrap();
+        }
+
+        let laptop = HistoryFilter {
+            hostname: Some("laptop".to_string()),
+            ..Default::default()
+        };
+        // the newest make ran on desktop, laptop still has its own
+        let res = db
+            .query(&HistoryFilter {
+                unique: true,
+                ..laptop.clone()
+            })
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].hostname, "laptop");
+        let res = db
+            .search(SearchMode::FullText, "make", &laptop)
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 1);
+        let res = db.fuzzy_search("mk", &laptop).await.unwrap();
+        assert_eq!(res.len(), 1);
+
+        let ellie = HistoryFilter {
+            username: Some("ellie".to_string()),
+            ..Default::default()
+        };
+        let res = db
+            .query(&HistoryFilter {
+                since: Some(now),
+                until: Some(now + chrono::Duration::seconds(10)),
+                ..ellie.clone()
+            })
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 3);
+        let res = db.search(SearchMode::Prefix, "ls", &ellie).await.unwrap();
+        assert_eq!(res[0].hostname, "desktop");
+    }
+
+    #[async_std::test]
+    async fn test_history_filter() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        let now = Utc::now();
+        let items = [
+            ("make", "/src/hiztery", 2_000_000_000, 0),
+            ("make", "/src/hiztery/sub", 10_000_000, 2),
+            ("ls", "/src/hiztery_old", 1_000_000, 0),
+            ("vim", "/src", -1, -1),
+        ];
+        for (idx, (cmd, cwd, duration, exit_status)) in items.iter().enumerate() {
+            let item = HistoryItem::new(
+                None,
+                cmd.to_string(),
+                cwd.to_string(),
+                *duration,
+                *exit_status,
+                Some(idx as i64 + 1),
+                now + chrono::Duration::seconds(idx as i64),
+            );
+            db.save(&item).await.unwrap();
+        }
+
+        let query = |filter: HistoryFilter| {
+            let db = db.clone();
+            async move {
+                db.query(&filter)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|i| i.cwd)
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        // a sibling that shares the prefix isn't in the tree
+        let res = query(HistoryFilter {
+            cwd: Some(CwdFilter::Tree("/src/hiztery/".to_string())),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(res, vec!["/src/hiztery/sub", "/src/hiztery"]);
+        let res = query(HistoryFilter {
+            cwd: Some(CwdFilter::Exact("/src".to_string())),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(res, vec!["/src"]);
+
+        // a command that never reported back is neither
+        let res = query(HistoryFilter {
+            exit: Some(ExitFilter::Failure),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(res, vec!["/src/hiztery/sub"]);
+        let res = query(HistoryFilter {
+            exit: Some(ExitFilter::Success),
+            max_duration: Some(1_000_000_000),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(res, vec!["/src/hiztery_old"]);
+        let res = query(HistoryFilter {
+            min_duration: Some(5_000_000),
+            oldest_first: true,
+            offset: Some(1),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(res, vec!["/src/hiztery/sub"]);
+
+        // search checks the filter before it picks the newest copy
+        let res = db
+            .search(
+                SearchMode::Prefix,
+                "make",
+                &HistoryFilter {
+                    exit: Some(ExitFilter::Success),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].cwd, "/src/hiztery");
+        let res = db
+            .search(
+                SearchMode::Regex,
+                "^(make|ls)$",
+                &HistoryFilter {
+                    offset: Some(1),
+                    limit: Some(1),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].command, "make");
+    }
+
+    #[async_std::test]
+    async fn test_rank_near() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        let now = Utc::now();
+        let items = [
+            ("cargo test", "/src/hiztery"),
+            ("make", "/src"),
+            ("cargo test", "/src/other"),
+            ("ls", "/src/hiztery_old"),
+            ("cargo build", "/"),
+        ];
+        for (idx, (cmd, cwd)) in items.iter().enumerate() {
+            let item = HistoryItem::new(
+                None,
+                cmd.to_string(),
+                cwd.to_string(),
+                0,
+                0,
+                Some(idx as i64 + 1),
+                now + chrono::Duration::seconds(idx as i64),
+            );
+            db.save(&item).await.unwrap();
+        }
+
+        let near = HistoryFilter {
+            near: Some("/src/hiztery/".to_string()),
+            ..Default::default()
+        };
+        let cwds = |res: Vec<HistoryItem>| res.into_iter().map(|i| i.cwd).collect::<Vec<_>>();
+
+        // here, then the parents closest first, then the newest of the rest
+        let res = db.query(&near).await.unwrap();
+        assert_eq!(
+            cwds(res),
+            vec![
+                "/src/hiztery",
+                "/src",
+                "/",
+                "/src/hiztery_old",
+                "/src/other"
+            ]
+        );
+
+        // the copy of cargo test that's kept is the one that ran here
+        let res = db.search(SearchMode::Prefix, "cargo", &near).await.unwrap();
+        assert_eq!(cwds(res), vec!["/src/hiztery", "/"]);
+        let res = db
+            .search(SearchMode::FullText, "cargo", &near)
+            .await
+            .unwrap();
+        assert_eq!(cwds(res), vec!["/src/hiztery", "/"]);
+        let res = db.fuzzy_search("cargo", &near).await.unwrap();
+        assert_eq!(res[0].item.cwd, "/src/hiztery");
+    }
+
+    #[async_std::test]
+    async fn test_stats() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        let now = Utc::now();
+        let runs = [
+            ("cargo test", "/src/hiztery", 1_000, 0),
+            ("cargo test", "/src/hiztery", 3_000, 101),
+            ("cargo test", "/src/hiztery", 2_000, 0),
+            ("cargo build", "/src/hiztery", 9_000, 0),
+            ("cargo build", "/src/hiztery", -1, -1),
+            ("ls -la", "/home/ellie", 10, 0),
+        ];
+        for (idx, (cmd, cwd, duration, exit_status)) in runs.iter().enumerate() {
+            let item = HistoryItem::new(
+                None,
+                cmd.to_string(),
+                cwd.to_string(),
+                *duration,
+                *exit_status,
+                Some(idx as i64 + 1),
+                now + chrono::Duration::seconds(idx as i64),
+            );
+            db.save(&item).await.unwrap();
+        }
+
+        let stats = db.stats(&HistoryFilter::default(), 2, 2).await.unwrap();
+        assert_eq!(stats.total, 6);
+        assert_eq!(
+            stats.commands[0],
+            Top {
+                name: "cargo test".to_string(),
+                count: 3
+            }
+        );
+        assert_eq!(stats.commands.len(), 2);
+        assert_eq!(stats.binaries[0].name, "cargo");
+        assert_eq!(stats.binaries[0].count, 5);
+        assert_eq!(stats.directories[0].name, "/src/hiztery");
+        let per_hour: i64 = stats.heatmap.iter().flat_map(|r| r.hours.iter()).sum();
+        assert_eq!(per_hour, 6);
+
+        // the build that never finished counts as a run but not a failure
+        let names: Vec<&str> = stats.slowest.iter().map(|c| c.command.as_str()).collect();
+        assert_eq!(names, vec!["cargo build", "cargo test"]);
+        assert_eq!(stats.slowest[1].avg_duration, 2_000);
+        assert_eq!(stats.slowest[1].p95_duration, 3_000);
+        assert_eq!(stats.slowest[0].runs, 2);
+        assert_eq!(stats.flakiest.len(), 1);
+        assert_eq!(stats.flakiest[0].failed, 1);
+        assert!((stats.flakiest[0].failure_rate - 1.0 / 3.0).abs() < 1e-9);
+
+        let here = HistoryFilter {
+            cwd: Some(CwdFilter::Exact("/home/ellie".to_string())),
+            ..Default::default()
+        };
+        let stats = db.stats(&here, 10, 1).await.unwrap();
+        assert_eq!(stats.total, 1);
+        assert_eq!(stats.binaries[0].name, "ls");
+        assert!(stats.flakiest.is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_perf() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        let day = Utc.with_ymd_and_hms(2021, 7, 21, 12, 0, 0).unwrap();
+        let runs = [(0, 100), (0, 300), (2, 500)];
+        let mut ids = vec![];
+        for (idx, (days, wall_time)) in runs.iter().enumerate() {
+            let item = HistoryItem::new(
+                None,
+                "cargo build".to_string(),
+                "/src/hiztery".to_string(),
+                *wall_time,
+                0,
+                Some(idx as i64 + 1),
+                day + chrono::Duration::days(*days) + chrono::Duration::seconds(idx as i64),
+            );
+            let id = db.save(&item).await.unwrap().unwrap();
+            db.save_perf(&Perf {
+                history_id: id,
+                wall_time: *wall_time,
+                user_time: Some(wall_time / 2),
+                sys_time: None,
+                max_rss: Some(1024 * (idx as i64 + 1)),
+            })
+            .await
+            .unwrap();
+            ids.push(id);
+        }
+
+        let filter = HistoryFilter::default();
+        let res = db.perf("cargo build", Period::Day, &filter).await.unwrap();
+        assert_eq!(res.len(), 2);
+        assert_eq!((res[0].runs, res[0].wall_time), (2, 200));
+        assert_eq!(res[0].user_time, Some(100));
+        assert_eq!(res[0].sys_time, None);
+        assert_eq!(res[0].max_rss, Some(2048));
+        assert_eq!(res[1].wall_time, 500);
+        let res = db.perf("cargo build", Period::Run, &filter).await.unwrap();
+        assert_eq!(res.len(), 3);
+
+        // iso weeks, which don't always start in the year they belong to. At
+        // noon the local day is the same one anywhere but the far pacific.
+        for (date, week) in [
+            ((2021, 1, 3), "2020-W53"),
+            ((2021, 1, 4), "2021-W01"),
+            ((2024, 12, 30), "2025-W01"),
+            ((2021, 7, 21), "2021-W29"),
+        ] {
+            let (y, m, d) = date;
+            let t = Utc.with_ymd_and_hms(y, m, d, 12, 0, 0).unwrap();
+            let (res,): (String,) = sqlx::query_as(&format!(
+                "select {} from (select ?1 as timestamp) h",
+                Period::Week.sql("h")
+            ))
+            .bind(t.timestamp_nanos())
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+            assert_eq!(res, week);
+        }
+
+        assert!(db
+            .perf("cargo", Period::Day, &filter)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // deleting the command takes its metrics along
+        db.delete_history_item(ids[2]).await.unwrap();
+        let res = db.perf("cargo build", Period::Run, &filter).await.unwrap();
+        assert_eq!(res.len(), 2);
+        let (left,): (i64,) = sqlx::query_as("select count(*) from performance_items")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(left, 2);
+    }
+
+    #[async_std::test]
+    async fn test_check_repairs_orphans() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        let id = new_history_item(&mut db, "cargo build")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(db.check(false).await.unwrap(), vec![]);
+
+        // what a tool that doesn't turn foreign keys on (the sqlite3 shell)
+        // can leave behind
+        for sql in [
+            "pragma foreign_keys = off",
+            "insert into performance_items(history_id, wall_time) values(999, 1)",
+            "insert into history_items(timestamp, duration, exit_status, command, cwd, session_id)
+                values(1, 0, 0, 'ls', '/', 77)",
+            "insert into history_fts(history_fts, rowid, command) values('delete', 1, 'cargo build')",
+            "pragma foreign_keys = on",
+        ] {
+            sqlx::query(sql).execute(&db.pool).await.unwrap();
+        }
+
+        let found = db.check(true).await.unwrap();
+        let tables: Vec<(&str, i64, bool)> = found
+            .iter()
+            .map(|p| (p.table.as_str(), p.rows, p.repaired))
+            .collect();
+        assert_eq!(
+            tables,
+            vec![
+                ("performance_items", 1, false),
+                ("history_items", 1, false),
+                ("history_fts", 1, false)
+            ]
+        );
+        // a dry run leaves it all be
+        assert_eq!(db.check(true).await.unwrap(), found);
+
+        assert_eq!(db.check(false).await.unwrap().len(), 3);
+        assert_eq!(db.check(false).await.unwrap(), vec![]);
+        assert!(db.session("77").await.unwrap().is_some());
+        let res = db
+            .search(SearchMode::FullText, "cargo", &HistoryFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(res[0].history_id, Some(id));
+    }
+
+    #[async_std::test]
+    async fn test_prune() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        let now = Utc::now();
+        let items = [
+            ("make", 400, 0),
+            ("make", 40, 2),
+            ("cargo test", 30, 101),
+            ("make", 20, 0),
+            ("ls", 1, 0),
+        ];
+        for (idx, (cmd, days, exit_status)) in items.iter().enumerate() {
+            let item = HistoryItem::new(
+                None,
+                cmd.to_string(),
+                "/src/hiztery".to_string(),
+                0,
+                *exit_status,
+                Some(idx as i64 + 1),
+                now - chrono::Duration::days(*days),
+            );
+            db.save(&item).await.unwrap();
+        }
+        let commands = |db: Sqlite| async move {
+            db.query(&HistoryFilter::default())
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|i| i.command)
+                .collect::<Vec<_>>()
+        };
+
+        let retention: RetentionSettings = toml::from_str(
+            "days = 365
+            failed_days = 14
+            dedupe = true
+            max_rows = 1",
+        )
+        .unwrap();
+        let counts = |pruned: Vec<Pruned>| pruned.iter().map(|p| p.rows).collect::<Vec<_>>();
+
+        // every rule counts what the ones before it left
+        let pruned = db.prune(&retention, true).await.unwrap();
+        assert_eq!(pruned[0].rule, "older than 365 days");
+        assert_eq!(counts(pruned), vec![1, 2, 0, 1]);
+        assert_eq!(commands(db.clone()).await.len(), 5);
+
+        let only_dupes = RetentionSettings {
+            dedupe: true,
+            ..Default::default()
+        };
+        assert_eq!(counts(db.prune(&only_dupes, false).await.unwrap()), vec![2]);
+        assert_eq!(commands(db.clone()).await, vec!["ls", "make", "cargo test"]);
+
+        let newest = RetentionSettings {
+            max_rows: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(counts(db.prune(&newest, false).await.unwrap()), vec![1]);
+        db.compact().await.unwrap();
+        assert_eq!(commands(db.clone()).await, vec!["ls", "make"]);
+    }
+
+    #[async_std::test]
+    async fn test_search_prefix() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        new_history_item(&mut db, "ls /home/ellie").await.unwrap();
+
+        let mut results = db
+            .search(SearchMode::Prefix, "ls", &HistoryFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+
+        results = db
+            .search(SearchMode::Prefix, "/home", &HistoryFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 0);
+
+        results = db
+            .search(SearchMode::Prefix, "ls  ", &HistoryFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 0);
+    }
+
+    #[async_std::test]
+    async fn test_search_fulltext() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        new_history_item(&mut db, "ls /home/ellie").await.unwrap();
+
+        let mut results = db
+            .search(SearchMode::FullText, "ls", &HistoryFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+
+        results = db
+            .search(SearchMode::FullText, "/home", &HistoryFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+
+        // whitespace only separates terms, it is not matched literally
+        results = db
+            .search(SearchMode::FullText, "ls  ", &HistoryFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+
+        results = db
+            .search(SearchMode::FullText, "ell*", &HistoryFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+
+        results = db
+            .search(SearchMode::FullText, "ls frank", &HistoryFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 0);
+    }
+
+    #[async_std::test]
+    async fn test_search_fulltext_ranking() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        new_history_item(&mut db, "git status").await.unwrap();
+        new_history_item(&mut db, "git log --oneline git")
+            .await
+            .unwrap();
+        new_history_item(&mut db, "cargo build").await.unwrap();
+
+        let results = db
+            .search(SearchMode::FullText, "git", &HistoryFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].command, "git log --oneline git");
+
+        let results = db
+            .search(
+                SearchMode::FullText,
+                "  ",
+                &HistoryFilter {
+                    limit: Some(1),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+    }
+
+    #[async_std::test]
+    async fn test_search_fulltext_follows_update_and_delete() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        new_history_item(&mut db, "cargo build").await.unwrap();
+
+        let mut item = db.last().await.unwrap();
+        item.command = "cargo test".to_string();
+        db.update(&item).await.unwrap();
+
+        let results = db
+            .search(SearchMode::FullText, "build", &HistoryFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 0);
+        let results = db
+            .search(SearchMode::FullText, "test", &HistoryFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+
+        db.delete_history_item(item.history_id.unwrap())
+            .await
+            .unwrap();
+        let results = db
+            .search(SearchMode::FullText, "test", &HistoryFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 0);
+    }
+
+    #[async_std::test]
+    async fn test_delete_matches() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        for cmd in ["ssh prod", "ls", "ssh prod", "ping prod"] {
+            new_history_item(&mut db, cmd).await.unwrap();
+        }
+
+        // search keeps one copy, a delete has to get them all
+        let all = HistoryFilter::default();
+        let results = db.search(SearchMode::FullText, "prod", &all).await.unwrap();
+        assert_eq!(results.len(), 2);
+        for mode in [SearchMode::FullText, SearchMode::Fuzzy, SearchMode::Regex] {
+            let results = db.matches(mode, "prod", &all).await.unwrap();
+            assert_eq!(results.len(), 3, "{}", mode);
+        }
+        let results = db.matches(SearchMode::Prefix, "ssh", &all).await.unwrap();
+        assert_eq!(results.len(), 2);
+
+        let ids: Vec<i64> = results.iter().filter_map(|h| h.history_id).collect();
+        assert_eq!(db.delete_history_items(&ids).await.unwrap(), 2);
+        let results = db
+            .matches(SearchMode::FullText, "prod", &all)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(db.history_count().await.unwrap(), 2);
+    }
+
+    #[async_std::test]
+    async fn test_search_fuzzy() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        new_history_item(&mut db, "ls /home/ellie").await.unwrap();
         new_history_item(&mut db, "ls /home/frank").await.unwrap();
         new_history_item(&mut db, "cd /home/ellie").await.unwrap();
         new_history_item(&mut db, "/home/ellie/.bin/rustup")
             .await
             .unwrap();
 
-        let mut results = db.search(None, SearchMode::Fuzzy, "ls /").await.unwrap();
+        let mut results = db
+            .search(SearchMode::Fuzzy, "ls /", &HistoryFilter::default())
+            .await
+            .unwrap();
         assert_eq!(results.len(), 2);
 
-        results = db.search(None, SearchMode::Fuzzy, "l/h/").await.unwrap();
+        results = db
+            .search(SearchMode::Fuzzy, "l/h/", &HistoryFilter::default())
+            .await
+            .unwrap();
         assert_eq!(results.len(), 2);
 
-        results = db.search(None, SearchMode::Fuzzy, "/h/e").await.unwrap();
+        results = db
+            .search(SearchMode::Fuzzy, "/h/e", &HistoryFilter::default())
+            .await
+            .unwrap();
         assert_eq!(results.len(), 3);
 
-        results = db.search(None, SearchMode::Fuzzy, "/hmoe/").await.unwrap();
+        // no exact match, but only a swapped pair away from /home/
+        results = db
+            .search(SearchMode::Fuzzy, "/hmoe/", &HistoryFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 4);
+
+        results = db
+            .search(SearchMode::Fuzzy, "/hxyz/", &HistoryFilter::default())
+            .await
+            .unwrap();
         assert_eq!(results.len(), 0);
 
         results = db
-            .search(None, SearchMode::Fuzzy, "ellie/home")
+            .search(SearchMode::Fuzzy, "ellie/home", &HistoryFilter::default())
             .await
             .unwrap();
         assert_eq!(results.len(), 0);
 
-        results = db.search(None, SearchMode::Fuzzy, "lsellie").await.unwrap();
+        results = db
+            .search(SearchMode::Fuzzy, "lsellie", &HistoryFilter::default())
+            .await
+            .unwrap();
         assert_eq!(results.len(), 1);
 
-        results = db.search(None, SearchMode::Fuzzy, " ").await.unwrap();
+        results = db
+            .search(SearchMode::Fuzzy, " ", &HistoryFilter::default())
+            .await
+            .unwrap();
         assert_eq!(results.len(), 3);
+
+        results = db
+            .search(
+                SearchMode::Fuzzy,
+                "/h/e",
+                &HistoryFilter {
+                    limit: Some(1),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+    }
+
+    #[async_std::test]
+    async fn test_search_regex() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        new_history_item(&mut db, "git push origin release/1.2")
+            .await
+            .unwrap();
+        new_history_item(&mut db, "git push origin main")
+            .await
+            .unwrap();
+        new_history_item(&mut db, "git push origin release/2.0")
+            .await
+            .unwrap();
+
+        let mut results = db
+            .search(
+                SearchMode::Regex,
+                "^git push .* release/.*",
+                &HistoryFilter::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].command, "git push origin release/2.0");
+
+        results = db
+            .search(
+                SearchMode::Regex,
+                "release/.*",
+                &HistoryFilter {
+                    limit: Some(1),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].command, "git push origin release/2.0");
+
+        results = db
+            .search(
+                SearchMode::Regex,
+                "release/.*",
+                &HistoryFilter {
+                    limit: Some(1),
+                    offset: Some(1),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].command, "git push origin release/1.2");
+
+        results = db
+            .search(SearchMode::Regex, "^push", &HistoryFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 0);
+
+        assert!(db
+            .search(SearchMode::Regex, "(", &HistoryFilter::default())
+            .await
+            .is_err());
+    }
+
+    #[async_std::test]
+    async fn test_fuzzy_search_non_ascii_and_typos() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        new_history_item(&mut db, "echo CAFÉ").await.unwrap();
+        new_history_item(&mut db, "cargo build").await.unwrap();
+        let all = HistoryFilter::default();
+
+        // LIKE wouldn't let É through for é
+        let res = db.fuzzy_search("fé", &all).await.unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].item.command, "echo CAFÉ");
+
+        let res = db.fuzzy_search("cagro", &all).await.unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].item.command, "cargo build");
+        // typos are only a fallback
+        let res = db.fuzzy_search("cargo", &all).await.unwrap();
+        assert_eq!(res.len(), 1);
+        assert!(db.fuzzy_search("cgx", &all).await.unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_fuzzy_search_ranking() {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        new_history_item(&mut db, "git checkout main")
+            .await
+            .unwrap();
+        new_history_item(&mut db, "cargo check").await.unwrap();
+        new_history_item(&mut db, "echo chicken").await.unwrap();
+
+        let results = db
+            .fuzzy_search("check", &HistoryFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].item.command, "cargo check");
+        assert_eq!(results[0].positions, vec![6, 7, 8, 9, 10]);
+
+        let results = db
+            .fuzzy_search("gcm", &HistoryFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].item.command, "git checkout main");
     }
 }
@@ -0,0 +1,333 @@
+use crate::history_item::HistoryItem;
+use crate::session::Session;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{AeadCore, XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::convert::TryInto;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// the key lives next to the database, like the log file
+pub const KEY_FILE: &str = "hiztery.key";
+
+const KEY_LEN: usize = 32;
+
+/// The secret every machine that shares history needs a copy of. Records are
+/// encrypted with XChaCha20-Poly1305 under a subkey of it and identified by an
+/// HMAC-SHA256 under another, so whoever holds the records learns nothing but
+/// how many there are.
+#[derive(Clone)]
+pub struct Key([u8; KEY_LEN]);
+
+/// One encrypted `HistoryItem`. `record_key` is the same for every copy of an
+/// item (it's derived from the UNIQUE(timestamp, cwd, command) columns), which
+/// lets a server tell copies apart without being able to read them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncryptedItem {
+    // the server's id, None until it's been stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub record_id: Option<i64>,
+    pub record_key: String,
+    // 0 while the command was still running, 1 once it has an exit status or
+    // a duration, so a finished copy can replace an unfinished one
+    pub version: i64,
+    pub nonce: String,
+    pub data: String,
+}
+
+// What actually gets encrypted. session_id only means something on the machine
+// that wrote the item, so the session it belongs to travels along with it.
+#[derive(Serialize, Deserialize)]
+struct Plaintext {
+    #[serde(flatten)]
+    item: HistoryItem,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    session: Option<Session>,
+}
+
+pub fn key_path(db_path: &Path) -> PathBuf {
+    db_path
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join(KEY_FILE)
+}
+
+fn invalid_key(msg: String) -> sqlx::Error {
+    sqlx::Error::Configuration(msg.into())
+}
+
+impl Key {
+    pub fn generate() -> Self {
+        let mut key = [0; KEY_LEN];
+        key.copy_from_slice(&XChaCha20Poly1305::generate_key(&mut OsRng));
+        Self(key)
+    }
+
+    /// The base64 form `key export` prints and `key import` takes.
+    pub fn encode(&self) -> String {
+        BASE64.encode(self.0)
+    }
+
+    pub fn decode(encoded: &str) -> Result<Self, sqlx::Error> {
+        let bytes = BASE64
+            .decode(encoded.trim())
+            .map_err(|e| invalid_key(format!("invalid key, expected base64: {}", e)))?;
+        let key = bytes.try_into().map_err(|b: Vec<u8>| {
+            invalid_key(format!(
+                "invalid key, expected {} bytes but got {}",
+                KEY_LEN,
+                b.len()
+            ))
+        })?;
+
+        Ok(Self(key))
+    }
+
+    pub fn load(path: &Path) -> Result<Option<Self>, sqlx::Error> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        Self::decode(&fs::read_to_string(path)?).map(Some)
+    }
+
+    pub fn load_or_create(path: &Path) -> Result<Self, sqlx::Error> {
+        if let Some(key) = Self::load(path)? {
+            return Ok(key);
+        }
+
+        log::debug!("creating a new key at {:?}", path);
+        let key = Self::generate();
+        key.save(path)?;
+        Ok(key)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), sqlx::Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut opts = fs::OpenOptions::new();
+        opts.write(true).create(true).truncate(true);
+        // nobody else has any business reading it
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+
+        let mut file = opts.open(path)?;
+        // the mode only counts for a new file, a key that's replaced keeps
+        // whatever the old file had otherwise
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        writeln!(file, "{}", self.encode())?;
+
+        Ok(())
+    }
+
+    fn subkey(&self, purpose: &[u8]) -> [u8; KEY_LEN] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.0).expect("any key length");
+        mac.update(purpose);
+        mac.finalize().into_bytes().into()
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.subkey(b"hiztery encryption").into())
+    }
+
+    pub fn record_key(&self, item: &HistoryItem) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.subkey(b"hiztery record key"))
+            .expect("any key length");
+        mac.update(&item.timestamp.timestamp_nanos().to_le_bytes());
+        mac.update(item.cwd.as_bytes());
+        mac.update(&[0]);
+        mac.update(item.command.as_bytes());
+
+        BASE64.encode(mac.finalize().into_bytes())
+    }
+
+    pub fn encrypt(
+        &self,
+        item: &HistoryItem,
+        session: Option<&Session>,
+    ) -> Result<EncryptedItem, sqlx::Error> {
+        // the ids are only meaningful on the machine that wrote them
+        let mut item = item.clone();
+        item.history_id = None;
+        let session = session.cloned().map(|mut s| {
+            s.session_id = None;
+            s
+        });
+
+        let record_key = self.record_key(&item);
+        let version = if item.duration >= 0 || item.exit_status >= 0 {
+            1
+        } else {
+            0
+        };
+        let plaintext = serde_json::to_vec(&Plaintext { item, session })
+            .map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+
+        // the record key and version go in as associated data, so a record
+        // can't be passed off under another one's key
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = associated_data(&record_key, version);
+        let data = self
+            .cipher()
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &plaintext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| sqlx::Error::Protocol("failed to encrypt history item".to_string()))?;
+
+        Ok(EncryptedItem {
+            record_id: None,
+            record_key,
+            version,
+            nonce: BASE64.encode(nonce),
+            data: BASE64.encode(data),
+        })
+    }
+
+    /// The item and, if it was encrypted with one, its session. Records
+    /// written before sessions existed only have the item.
+    pub fn decrypt(
+        &self,
+        record: &EncryptedItem,
+    ) -> Result<(HistoryItem, Option<Session>), sqlx::Error> {
+        let undecryptable = || {
+            sqlx::Error::Protocol(
+                "failed to decrypt history item, was it written with a different key? \
+                 `hiztery key export` on the machine that wrote it and `hiztery key import` \
+                 here share it"
+                    .to_string(),
+            )
+        };
+
+        let nonce = BASE64.decode(&record.nonce).map_err(|_| undecryptable())?;
+        if nonce.len() != 24 {
+            return Err(undecryptable());
+        }
+        let data = BASE64.decode(&record.data).map_err(|_| undecryptable())?;
+        let aad = associated_data(&record.record_key, record.version);
+
+        let plaintext = self
+            .cipher()
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &data,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| undecryptable())?;
+
+        let plaintext: Plaintext =
+            serde_json::from_slice(&plaintext).map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+        Ok((plaintext.item, plaintext.session))
+    }
+}
+
+fn associated_data(record_key: &str, version: i64) -> Vec<u8> {
+    let mut aad = record_key.as_bytes().to_vec();
+    aad.extend_from_slice(&version.to_le_bytes());
+    aad
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    fn item() -> HistoryItem {
+        HistoryItem::new(
+            Some(9),
+            "curl -H 'Authorization: hunter2' internal.example".to_string(),
+            "/home/ellie".to_string(),
+            1_000,
+            0,
+            Some(3),
+            Utc.timestamp_opt(1_600_000_000, 42).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_encrypt_round_trip() {
+        let key = Key::generate();
+        let record = key.encrypt(&item(), None).unwrap();
+        assert!(!record.data.contains("hunter2"));
+        assert_eq!(record.version, 1);
+
+        let (decrypted, session) = key.decrypt(&record).unwrap();
+        assert_eq!(session, None);
+        assert_eq!(decrypted.command, item().command);
+        assert_eq!(decrypted.timestamp, item().timestamp);
+        assert_eq!(decrypted.history_id, None);
+
+        // same item, same record key, but never the same ciphertext
+        let again = key.encrypt(&item(), None).unwrap();
+        assert_eq!(again.record_key, record.record_key);
+        assert_ne!(again.data, record.data);
+
+        let mut session = Session::new("zsh", "/dev/pts/3");
+        session.session_id = Some(3);
+        let record = key.encrypt(&item(), Some(&session)).unwrap();
+        let (_, decrypted) = key.decrypt(&record).unwrap();
+        let decrypted = decrypted.unwrap();
+        assert_eq!(decrypted.uuid, session.uuid);
+        assert_eq!(decrypted.session_id, None);
+    }
+
+    #[test]
+    fn test_decrypt_rejects_wrong_key_and_tampering() {
+        let key = Key::generate();
+        let record = key.encrypt(&item(), None).unwrap();
+
+        assert!(Key::generate().decrypt(&record).is_err());
+
+        let mut downgraded = record.clone();
+        downgraded.version = 0;
+        assert!(key.decrypt(&downgraded).is_err());
+
+        let mut other = item();
+        other.command = "ls".to_string();
+        let mut swapped = key.encrypt(&other, None).unwrap();
+        swapped.record_key = record.record_key;
+        assert!(key.decrypt(&swapped).is_err());
+    }
+
+    #[test]
+    fn test_key_encoding() {
+        let key = Key::generate();
+        let decoded = Key::decode(&format!("{}\n", key.encode())).unwrap();
+        assert_eq!(decoded.0, key.0);
+
+        assert!(Key::decode("not base64!").is_err());
+        assert!(Key::decode(&BASE64.encode([1u8; 16])).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_save_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("hiztery-key-{}", std::process::id()));
+        fs::write(&path, "old key\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let key = Key::generate();
+        key.save(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let saved = Key::load(&path).unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(saved.0, key.0);
+    }
+}
@@ -0,0 +1,196 @@
+use crate::encryption::Key;
+use crate::history_item::HistoryItem;
+use crate::output::{self, OutputFormat};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Jsonl,
+    Csv,
+    Bash,
+    Zsh,
+    Nu,
+}
+
+impl ExportFormat {
+    pub const NAMES: &'static [&'static str] = &["jsonl", "csv", "bash", "zsh", "nu"];
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(ExportFormat::Jsonl),
+            "csv" => Ok(ExportFormat::Csv),
+            "bash" => Ok(ExportFormat::Bash),
+            "zsh" => Ok(ExportFormat::Zsh),
+            "nu" | "nushell" => Ok(ExportFormat::Nu),
+            _ => Err(format!(
+                "unsupported export format {}, expected one of {}",
+                s,
+                ExportFormat::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// Write `items` in the given format. Only jsonl and csv keep every field,
+/// the shell formats keep what the shell itself would have written.
+pub fn write(items: &[HistoryItem], format: ExportFormat, out: &mut impl Write) -> io::Result<()> {
+    match format {
+        ExportFormat::Jsonl => {
+            for item in items {
+                serde_json::to_writer(&mut *out, item)?;
+                out.write_all(b"\n")?;
+            }
+        }
+        ExportFormat::Csv => out.write_all(output::render(items, OutputFormat::Csv)?.as_bytes())?,
+        ExportFormat::Bash => {
+            // the HISTTIMEFORMAT style, a multi-line command just runs on
+            // until the next timestamp
+            for item in items {
+                writeln!(out, "#{}\n{}", item.timestamp.timestamp(), item.command)?;
+            }
+        }
+        ExportFormat::Zsh => {
+            for item in items {
+                let command = item.command.replace('\n', "\\\n");
+                out.write_all(
+                    format!(": {}:{};", item.timestamp.timestamp(), zsh_duration(item)).as_bytes(),
+                )?;
+                out.write_all(&metafy(command.as_bytes()))?;
+                out.write_all(b"\n")?;
+            }
+        }
+        ExportFormat::Nu => {
+            // reedline's plaintext history escapes newlines this way
+            for item in items {
+                writeln!(out, "{}", item.command.replace('\n', "<\\n>"))?;
+            }
+        }
+    }
+
+    out.flush()
+}
+
+/// jsonl again, but every line is an `EncryptedItem` only `key` can read.
+pub fn write_encrypted(items: &[HistoryItem], key: &Key, out: &mut impl Write) -> io::Result<()> {
+    for item in items {
+        let record = key.encrypt(item, None).map_err(io::Error::other)?;
+        serde_json::to_writer(&mut *out, &record)?;
+        out.write_all(b"\n")?;
+    }
+
+    out.flush()
+}
+
+// zsh only keeps whole seconds and has no notion of an unknown duration
+fn zsh_duration(item: &HistoryItem) -> i64 {
+    item.duration.max(0) / 1_000_000_000
+}
+
+// the reverse of import::unmetafy
+fn metafy(bytes: &[u8]) -> Vec<u8> {
+    const META: u8 = 0x83;
+    const MARKER: u8 = 0xa2;
+
+    let mut out = Vec::with_capacity(bytes.len());
+    for b in bytes {
+        if *b == 0 || (META..=MARKER).contains(b) {
+            out.push(META);
+            out.push(b ^ 32);
+        } else {
+            out.push(*b);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::import;
+    use chrono::{TimeZone, Utc};
+
+    fn items() -> Vec<HistoryItem> {
+        vec![
+            HistoryItem::new(
+                Some(3),
+                "cargo build".to_string(),
+                "/home/ellie/hiztery".to_string(),
+                2_500_000_000,
+                0,
+                Some(11),
+                Utc.timestamp_opt(1_600_000_000, 123_456_789).unwrap(),
+            ),
+            HistoryItem::new(
+                Some(4),
+                "for i in à b\ndo echo $i\ndone".to_string(),
+                "/tmp".to_string(),
+                -1,
+                -1,
+                Some(12),
+                Utc.timestamp_opt(1_600_000_010, 0).unwrap(),
+            ),
+        ]
+    }
+
+    fn export(format: ExportFormat) -> String {
+        let mut out = vec![];
+        write(&items(), format, &mut out).unwrap();
+        String::from_utf8_lossy(&out).into_owned()
+    }
+
+    #[test]
+    fn test_jsonl_round_trip() {
+        let exported = export(ExportFormat::Jsonl);
+        assert_eq!(exported.lines().count(), 2);
+
+        let imported = import::parse_jsonl(&exported, None).unwrap();
+        for (a, b) in imported.iter().zip(items().iter()) {
+            assert_eq!(a.history_id, b.history_id);
+            assert_eq!(a.command, b.command);
+            assert_eq!(a.cwd, b.cwd);
+            assert_eq!(a.duration, b.duration);
+            assert_eq!(a.exit_status, b.exit_status);
+            assert_eq!(a.session_id, b.session_id);
+            assert_eq!(a.timestamp, b.timestamp);
+        }
+    }
+
+    #[test]
+    fn test_encrypted_jsonl_round_trip() {
+        let key = Key::generate();
+        let mut out = vec![];
+        write_encrypted(&items(), &key, &mut out).unwrap();
+        let exported = String::from_utf8(out).unwrap();
+        assert!(!exported.contains("cargo build"));
+
+        assert!(import::parse_jsonl(&exported, None).is_err());
+        let imported = import::parse_jsonl(&exported, Some(&key)).unwrap();
+        assert_eq!(imported[1].command, items()[1].command);
+        assert_eq!(imported[1].history_id, None);
+    }
+
+    #[test]
+    fn test_shell_formats() {
+        assert_eq!(
+            export(ExportFormat::Bash),
+            "#1600000000\ncargo build\n#1600000010\nfor i in à b\ndo echo $i\ndone\n"
+        );
+        assert_eq!(
+            export(ExportFormat::Nu),
+            "cargo build\nfor i in à b<\\n>do echo $i<\\n>done\n"
+        );
+
+        let mut zsh = vec![];
+        write(&items(), ExportFormat::Zsh, &mut zsh).unwrap();
+        let imported = import::parse_zsh(&import::unmetafy(&zsh), Utc::now());
+        assert_eq!(imported[0].duration, 2_000_000_000);
+        assert_eq!(imported[1].command, items()[1].command);
+        assert_eq!(imported[1].timestamp.timestamp(), 1_600_000_010);
+    }
+}
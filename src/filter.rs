@@ -0,0 +1,203 @@
+use chrono::{DateTime, Utc};
+use sqlx::{QueryBuilder, Sqlite};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CwdFilter {
+    // run in exactly this directory
+    Exact(String),
+    // run in this directory or anywhere below it
+    Tree(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitFilter {
+    Success,
+    // anything but 0, commands that never reported back (-1) aren't failures
+    Failure,
+    Code(i64),
+}
+
+/// What `Database::query` and `Database::search` narrow history down to.
+/// Everything that's set has to match, the default matches all of it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HistoryFilter {
+    pub cwd: Option<CwdFilter>,
+    pub exit: Option<ExitFilter>,
+    pub session_id: Option<i64>,
+    pub hostname: Option<String>,
+    pub username: Option<String>,
+    // both ends are included
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    // in nanoseconds, like duration, a command with an unknown duration
+    // matches neither
+    pub min_duration: Option<i64>,
+    pub max_duration: Option<i64>,
+    // only the newest copy of every command, search always does this
+    pub unique: bool,
+    // rank commands run in this directory first, then the ones run in its
+    // parents, closest first, and only then everything else
+    pub near: Option<String>,
+    pub oldest_first: bool,
+    pub offset: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+impl HistoryFilter {
+    /// Append an ` and ...` for everything that's set, against the
+    /// history_items columns of `table`. With `unique` that includes the
+    /// newest copy check, which only looks at copies that match as well.
+    pub fn push_conditions(&self, qb: &mut QueryBuilder<'_, Sqlite>, table: &str) {
+        self.push_columns(qb, table);
+
+        if !self.unique {
+            return;
+        }
+        if self.near.is_none() {
+            qb.push(format!(
+                " and {t}.timestamp = (select max(u.timestamp) from history_items u where u.command = {t}.command",
+                t = table
+            ));
+            self.push_columns(qb, "u");
+            qb.push(")");
+        } else {
+            // the copy that's kept is the one run closest, or it would be
+            // ranked by wherever the command happened to run last
+            qb.push(format!(
+                " and {t}.history_id = (select u.history_id from history_items u where u.command = {t}.command",
+                t = table
+            ));
+            self.push_columns(qb, "u");
+            self.push_order(qb, "u", "u.timestamp desc");
+            qb.push(" limit 1)");
+        }
+    }
+
+    /// ` order by ...` with `order` after the commands closest to `near`.
+    pub fn push_order(&self, qb: &mut QueryBuilder<'_, Sqlite>, table: &str, order: &str) {
+        qb.push(" order by ");
+        if let Some(near) = &self.near {
+            // how long the matching directory is, so deeper is closer, and -1
+            // when it's neither near nor a parent of it. The trailing / keeps
+            // /src/hiztery from being a parent of /src/hiztery_old.
+            let dir = format!("(rtrim({}.cwd, '/') || '/')", table);
+            qb.push("case when substr(")
+                .push_bind(format!("{}/", near.trim_end_matches('/')))
+                .push(format!(
+                    ", 1, length({d})) = {d} then length({d}) else -1 end desc, ",
+                    d = dir
+                ));
+        }
+        qb.push(order);
+    }
+
+    // the same ranking as push_order, for whatever is ranked outside of
+    // sqlite: how long the matching directory is, -1 when nothing matches
+    pub fn closeness(&self, cwd: &str) -> i64 {
+        let near = match &self.near {
+            Some(near) => format!("{}/", near.trim_end_matches('/')),
+            None => return -1,
+        };
+
+        let dir = format!("{}/", cwd.trim_end_matches('/'));
+        if near.starts_with(&dir) {
+            dir.len() as i64
+        } else {
+            -1
+        }
+    }
+
+    // how many results the outside-of-sqlite searches need before they can
+    // stop looking, None for all of them
+    pub fn wanted(&self) -> Option<usize> {
+        let limit = self.limit?.max(0) as usize;
+        Some(limit.saturating_add(self.offset.unwrap_or(0).max(0) as usize))
+    }
+
+    fn push_columns(&self, qb: &mut QueryBuilder<'_, Sqlite>, t: &str) {
+        match &self.cwd {
+            Some(CwdFilter::Exact(dir)) => {
+                qb.push(format!(" and {}.cwd = ", t)).push_bind(dir.clone());
+            }
+            Some(CwdFilter::Tree(dir)) => {
+                // no LIKE, a directory is full of _ and % often enough
+                let dir = dir.trim_end_matches('/').to_string();
+                qb.push(format!(" and ({}.cwd = ", t))
+                    .push_bind(dir.clone())
+                    .push(format!(" or substr({}.cwd, 1, ", t))
+                    .push_bind(dir.len() as i64 + 1)
+                    .push(") = ")
+                    .push_bind(format!("{}/", dir))
+                    .push(")");
+            }
+            None => {}
+        }
+
+        match self.exit {
+            Some(ExitFilter::Success) => {
+                qb.push(format!(" and {}.exit_status = 0", t));
+            }
+            Some(ExitFilter::Failure) => {
+                qb.push(format!(" and {}.exit_status not in (0, -1)", t));
+            }
+            Some(ExitFilter::Code(code)) => {
+                qb.push(format!(" and {}.exit_status = ", t))
+                    .push_bind(code);
+            }
+            None => {}
+        }
+
+        if let Some(session_id) = self.session_id {
+            qb.push(format!(" and {}.session_id = ", t))
+                .push_bind(session_id);
+        }
+        if let Some(hostname) = &self.hostname {
+            qb.push(format!(" and {}.hostname = ", t))
+                .push_bind(hostname.clone());
+        }
+        if let Some(username) = &self.username {
+            qb.push(format!(" and {}.username = ", t))
+                .push_bind(username.clone());
+        }
+        if let Some(since) = self.since {
+            qb.push(format!(" and {}.timestamp >= ", t))
+                .push_bind(since.timestamp_nanos());
+        }
+        if let Some(until) = self.until {
+            qb.push(format!(" and {}.timestamp <= ", t))
+                .push_bind(until.timestamp_nanos());
+        }
+        if let Some(min) = self.min_duration {
+            qb.push(format!(" and {}.duration >= ", t)).push_bind(min);
+        }
+        if let Some(max) = self.max_duration {
+            qb.push(format!(
+                " and {t}.duration >= 0 and {t}.duration <= ",
+                t = t
+            ))
+            .push_bind(max);
+        }
+    }
+
+    /// ` limit ... offset ...`, sqlite only takes an offset after a limit.
+    pub fn push_paging(&self, qb: &mut QueryBuilder<'_, Sqlite>) {
+        if self.limit.is_none() && self.offset.is_none() {
+            return;
+        }
+
+        qb.push(" limit ")
+            .push_bind(self.limit.unwrap_or(-1))
+            .push(" offset ")
+            .push_bind(self.offset.unwrap_or(0));
+    }
+
+    // what's left once the paging is done here instead of by sqlite, for
+    // the searches that can only rank or match outside of it
+    pub fn page<T>(&self, items: Vec<T>) -> Vec<T> {
+        items
+            .into_iter()
+            .skip(self.offset.unwrap_or(0).max(0) as usize)
+            .take(self.limit.map_or(usize::MAX, |l| l.max(0) as usize))
+            .collect()
+    }
+}
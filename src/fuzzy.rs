@@ -0,0 +1,398 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+use chrono::{DateTime, Utc};
+
+use crate::history_item::HistoryItem;
+
+// Scoring follows the same shape as fzf/skim: every matched character is
+// worth SCORE_MATCH, gaps between matches cost a little, and matches that
+// start a word or continue a run of matches earn a bonus. The constants are
+// fzf's.
+const SCORE_MATCH: i64 = 16;
+const SCORE_GAP_START: i64 = -3;
+const SCORE_GAP_EXTENSION: i64 = -1;
+
+const BONUS_BOUNDARY_WHITE: i64 = 10;
+const BONUS_BOUNDARY_DELIMITER: i64 = 9;
+const BONUS_BOUNDARY: i64 = 8;
+const BONUS_CAMEL: i64 = 7;
+const BONUS_CONSECUTIVE: i64 = 4;
+const BONUS_FIRST_CHAR_MULTIPLIER: i64 = 2;
+
+const NO_MATCH: i64 = i64::MIN / 2;
+
+// A pattern this long that matches nothing may have a typo in it, and gets a
+// second try with one of its characters left out. That covers a wrong, an
+// extra and two swapped characters, a missing one is a subsequence anyway.
+pub const MIN_TYPO_LEN: usize = 4;
+const SCORE_TYPO: i64 = -2 * SCORE_MATCH;
+
+#[derive(Debug, Clone)]
+pub struct FuzzyMatch {
+    pub item: HistoryItem,
+    pub score: i64,
+    // char offsets into item.command, handy for highlighting
+    pub positions: Vec<usize>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum CharClass {
+    White,
+    Delimiter,
+    NonWord,
+    Lower,
+    Upper,
+    Number,
+}
+
+fn char_class(c: char) -> CharClass {
+    if c.is_whitespace() {
+        CharClass::White
+    } else if "/,:;|-_.=".contains(c) {
+        CharClass::Delimiter
+    } else if c.is_lowercase() {
+        CharClass::Lower
+    } else if c.is_uppercase() {
+        CharClass::Upper
+    } else if c.is_numeric() {
+        CharClass::Number
+    } else if c.is_alphabetic() {
+        CharClass::Lower
+    } else {
+        CharClass::NonWord
+    }
+}
+
+fn bonus_for(prev: CharClass, class: CharClass) -> i64 {
+    use CharClass::*;
+
+    match (prev, class) {
+        (_, White) | (_, Delimiter) | (_, NonWord) => 0,
+        (White, _) => BONUS_BOUNDARY_WHITE,
+        (Delimiter, _) => BONUS_BOUNDARY_DELIMITER,
+        (NonWord, _) => BONUS_BOUNDARY,
+        (Lower, Upper) | (Number, Upper) | (Lower, Number) | (Upper, Number) => BONUS_CAMEL,
+        _ => 0,
+    }
+}
+
+// smart case: the match is case sensitive only if the pattern has an
+// uppercase character in it
+fn case_sensitive(pattern: &str) -> bool {
+    pattern.chars().any(char::is_uppercase)
+}
+
+fn chars_match(p: char, t: char, case_sensitive: bool) -> bool {
+    if case_sensitive {
+        p == t
+    } else {
+        t.to_lowercase().eq(p.to_lowercase())
+    }
+}
+
+/// Score `text` against `pattern` as an in-order subsequence. Returns `None`
+/// when not every pattern character can be found, otherwise the best score
+/// and the char offsets of the characters that matched.
+pub fn score(pattern: &str, text: &str) -> Option<(i64, Vec<usize>)> {
+    let chars: Vec<char> = pattern.chars().collect();
+    score_chars(&chars, text, case_sensitive(pattern))
+}
+
+/// Like score, but with one pattern character allowed to be missing from
+/// `text`, for a pattern that's a typo away from matching. Such a match
+/// always scores less than it would without the typo.
+pub fn score_typo(pattern: &str, text: &str) -> Option<(i64, Vec<usize>)> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let case_sensitive = case_sensitive(pattern);
+
+    (0..chars.len())
+        .filter_map(|skip| {
+            let mut variant = chars.clone();
+            variant.remove(skip);
+            score_chars(&variant, text, case_sensitive)
+        })
+        .max_by_key(|(score, _)| *score)
+        .map(|(score, positions)| (score + SCORE_TYPO, positions))
+}
+
+/// Every way to leave one character out of `pattern`, what score_typo
+/// tries. Empty for patterns too short to guess at.
+pub fn typo_variants(pattern: &str) -> Vec<String> {
+    let chars: Vec<char> = pattern.chars().collect();
+    if chars.len() < MIN_TYPO_LEN {
+        return vec![];
+    }
+
+    let mut variants: Vec<String> = (0..chars.len())
+        .map(|skip| {
+            let mut variant = chars.clone();
+            variant.remove(skip);
+            variant.into_iter().collect()
+        })
+        .collect();
+    variants.dedup();
+    variants
+}
+
+fn score_chars(pattern: &[char], text: &str, case_sensitive: bool) -> Option<(i64, Vec<usize>)> {
+    let text: Vec<char> = text.chars().collect();
+    let (m, n) = (pattern.len(), text.len());
+
+    if m == 0 {
+        return Some((0, vec![]));
+    }
+    if m > n {
+        return None;
+    }
+
+    let mut bonus = Vec::with_capacity(n);
+    let mut prev = CharClass::White;
+    for c in &text {
+        let class = char_class(*c);
+        bonus.push(bonus_for(prev, class));
+        prev = class;
+    }
+
+    // scores[i][j] is the best score with pattern[i] matched at text[j],
+    // from[i][j] is where pattern[i - 1] was matched to get there and
+    // chunk[i][j] is the bonus a run of consecutive matches carries along, so
+    // the rest of a word that started on a boundary keeps the boundary bonus
+    let mut scores = vec![vec![NO_MATCH; n]; m];
+    let mut from = vec![vec![0usize; n]; m];
+    let mut chunk = vec![vec![0i64; n]; m];
+
+    for i in 0..m {
+        // best score for matching pattern[i - 1] somewhere before j - 1,
+        // including the gap penalty for the characters skipped since then
+        let mut best_gap = NO_MATCH;
+        let mut best_gap_at = 0;
+
+        for j in 0..n {
+            if i > 0 && j >= 2 {
+                best_gap += SCORE_GAP_EXTENSION;
+                let candidate = scores[i - 1][j - 2] + SCORE_GAP_START;
+                if candidate > best_gap {
+                    best_gap = candidate;
+                    best_gap_at = j - 2;
+                }
+            }
+
+            if !chars_match(pattern[i], text[j], case_sensitive) {
+                continue;
+            }
+
+            if i == 0 {
+                scores[i][j] = SCORE_MATCH + bonus[j] * BONUS_FIRST_CHAR_MULTIPLIER;
+                chunk[i][j] = bonus[j];
+                continue;
+            }
+
+            if j == 0 {
+                continue;
+            }
+
+            let chunk_bonus = chunk[i - 1][j - 1].max(bonus[j]).max(BONUS_CONSECUTIVE);
+            let consecutive = scores[i - 1][j - 1] + SCORE_MATCH + chunk_bonus;
+            let gap = best_gap + SCORE_MATCH + bonus[j];
+
+            if consecutive >= gap {
+                scores[i][j] = consecutive;
+                from[i][j] = j - 1;
+                chunk[i][j] = chunk_bonus;
+            } else {
+                scores[i][j] = gap;
+                from[i][j] = best_gap_at;
+                chunk[i][j] = bonus[j];
+            }
+        }
+    }
+
+    let (mut j, best) = scores[m - 1]
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, s)| **s)
+        .map(|(j, s)| (j, *s))?;
+
+    if best <= NO_MATCH / 2 {
+        return None;
+    }
+
+    let mut positions = vec![0; m];
+    for i in (0..m).rev() {
+        positions[i] = j;
+        j = from[i][j];
+    }
+
+    Some((best, positions))
+}
+
+/// The best `keep` matches out of however many candidates get pushed, best
+/// first: the closest to the filter's directory, then the highest score,
+/// then the newest command. Only those are held on to, not every candidate.
+pub struct BestMatches {
+    keep: usize,
+    heap: BinaryHeap<Reverse<Ranked>>,
+}
+
+// a match and how close it was run to where the search asked for
+struct Ranked {
+    closeness: i64,
+    m: FuzzyMatch,
+}
+
+impl Ranked {
+    fn key(&self) -> (i64, i64, DateTime<Utc>) {
+        (self.closeness, self.m.score, self.m.item.timestamp)
+    }
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Ranked {}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl BestMatches {
+    pub fn new(keep: usize) -> Self {
+        BestMatches {
+            keep,
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    pub fn push(&mut self, closeness: i64, m: FuzzyMatch) {
+        let ranked = Ranked { closeness, m };
+
+        if self.heap.len() < self.keep {
+            self.heap.push(Reverse(ranked));
+        } else if let Some(mut worst) = self.heap.peek_mut() {
+            if ranked > worst.0 {
+                *worst = Reverse(ranked);
+            }
+        }
+    }
+
+    pub fn into_vec(self) -> Vec<FuzzyMatch> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(ranked)| ranked.m)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_score_subsequence() {
+        assert!(score("lsh", "ls /home").is_some());
+        assert!(score("hsl", "ls /home").is_none());
+        assert!(score("", "ls /home").is_some());
+        assert!(score("ls /home/ellie", "ls").is_none());
+    }
+
+    #[test]
+    fn test_score_positions() {
+        let (_, positions) = score("gco", "git checkout").unwrap();
+        assert_eq!(positions, vec![0, 4, 9]);
+
+        // the consecutive "che" beats picking up the first c/h/e it sees
+        let (_, positions) = score("che", "cargo check").unwrap();
+        assert_eq!(positions, vec![6, 7, 8]);
+    }
+
+    #[test]
+    fn test_score_prefers_boundaries_and_runs() {
+        let (boundary, _) = score("gp", "git push").unwrap();
+        let (middle, _) = score("gp", "dig up").unwrap();
+        assert!(boundary > middle);
+
+        let (run, _) = score("build", "cargo build").unwrap();
+        let (scattered, _) = score("build", "bu-i-l-d").unwrap();
+        assert!(run > scattered);
+    }
+
+    #[test]
+    fn test_score_smart_case() {
+        assert!(score("readme", "cat README.md").is_some());
+        assert!(score("README", "cat readme.md").is_none());
+        assert!(score("README", "cat README.md").is_some());
+        assert!(score("é", "echo É").is_some());
+    }
+
+    #[test]
+    fn test_score_typo() {
+        // wrong, extra and swapped characters
+        for typo in ["carbo", "carrgo", "cagro"] {
+            assert!(score(typo, "cargo build").is_none());
+            let (_, positions) = score_typo(typo, "cargo build").unwrap();
+            assert_eq!(positions.len(), typo.chars().count() - 1);
+        }
+
+        let (exact, _) = score("cargo", "cargo build").unwrap();
+        let (typo, _) = score_typo("carbo", "cargo build").unwrap();
+        assert!(typo < exact);
+
+        assert!(typo_variants("gco").is_empty());
+        assert_eq!(typo_variants("mmake"), vec!["make", "mmke", "mmae", "mmak"]);
+    }
+
+    #[test]
+    fn test_best_matches_keeps_the_best() {
+        use chrono::TimeZone;
+
+        let m = |command: &str, score: i64, secs: i64| FuzzyMatch {
+            item: HistoryItem::new(
+                None,
+                command.to_string(),
+                "/".to_string(),
+                0,
+                0,
+                None,
+                Utc.timestamp_nanos(secs),
+            ),
+            score,
+            positions: vec![],
+        };
+
+        let mut best = BestMatches::new(3);
+        best.push(-1, m("low", 1, 0));
+        best.push(-1, m("old", 5, 0));
+        best.push(-1, m("new", 5, 1));
+        best.push(-1, m("high", 9, 0));
+        best.push(2, m("near", 0, 0));
+        best.push(-1, m("lower", 0, 0));
+
+        let commands: Vec<String> = best
+            .into_vec()
+            .into_iter()
+            .map(|m| m.item.command)
+            .collect();
+        assert_eq!(commands, vec!["near", "high", "new"]);
+
+        let mut none = BestMatches::new(0);
+        none.push(-1, m("ls", 1, 0));
+        assert!(none.is_empty());
+    }
+}
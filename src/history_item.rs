@@ -1,8 +1,9 @@
+use crate::session::UNKNOWN_SESSION_ID;
 use chrono::Utc;
 use core::hash::{Hash, Hasher};
-use std::process;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Ord, PartialOrd, sqlx::FromRow)]
+#[derive(Debug, Clone, Ord, PartialOrd, sqlx::FromRow, Serialize, Deserialize)]
 pub struct HistoryItem {
     pub history_id: Option<i64>,
     pub command: String,
@@ -11,6 +12,12 @@ pub struct HistoryItem {
     pub exit_status: i64,
     pub session_id: i64,
     pub timestamp: chrono::DateTime<Utc>,
+    // where the command ran, empty until it's saved (which fills in this
+    // machine) or when nobody knows
+    #[serde(default)]
+    pub hostname: String,
+    #[serde(default)]
+    pub username: String,
 }
 
 impl HistoryItem {
@@ -23,7 +30,7 @@ impl HistoryItem {
         session_id: Option<i64>,
         timestamp: chrono::DateTime<Utc>,
     ) -> Self {
-        let session_id = session_id.unwrap_or_else(|| process::id().into());
+        let session_id = session_id.unwrap_or(UNKNOWN_SESSION_ID);
 
         Self {
             history_id,
@@ -33,6 +40,8 @@ impl HistoryItem {
             exit_status,
             session_id,
             timestamp,
+            hostname: String::new(),
+            username: String::new(),
         }
     }
 }
@@ -0,0 +1,162 @@
+use log::warn;
+use regex::Regex;
+use serde::Deserialize;
+
+// the [ignore] table in the config file, HISTCONTROL and HISTIGNORE for
+// everything hiztery saves
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct IgnoreSettings {
+    // HISTCONTROL=ignorespace, skip commands that start with a space
+    pub space: bool,
+    // HISTCONTROL=ignoredups, skip a command that's the same as the one
+    // before it in the same session
+    pub dups: bool,
+    // HISTIGNORE, globs that have to match the whole command
+    pub patterns: Vec<String>,
+    // the same, but regexes that match anywhere unless they're anchored
+    pub regexes: Vec<String>,
+}
+
+impl Default for IgnoreSettings {
+    fn default() -> Self {
+        Self {
+            space: true,
+            dups: true,
+            patterns: vec![],
+            regexes: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IgnoreRules {
+    pub space: bool,
+    pub dups: bool,
+    patterns: Vec<Regex>,
+}
+
+impl Default for IgnoreRules {
+    fn default() -> Self {
+        Self::new(&IgnoreSettings::default())
+    }
+}
+
+impl IgnoreRules {
+    // like the scrub patterns, a bad one is skipped with a warning
+    pub fn new(settings: &IgnoreSettings) -> Self {
+        let globs = settings.patterns.iter().map(|g| (g, glob_to_regex(g)));
+        let regexes = settings.regexes.iter().map(|r| (r, r.clone()));
+
+        let patterns = globs
+            .chain(regexes)
+            .filter_map(|(pattern, re)| match Regex::new(&re) {
+                Ok(re) => Some(re),
+                Err(e) => {
+                    warn!("ignoring ignore pattern {}: {}", pattern, e);
+                    None
+                }
+            })
+            .collect();
+
+        Self {
+            space: settings.space,
+            dups: settings.dups,
+            patterns,
+        }
+    }
+
+    /// Whether `command` should never be saved. Duplicates need to know what
+    /// came before, so they're checked where the history is.
+    pub fn ignores(&self, command: &str) -> bool {
+        if command.trim().is_empty() {
+            return true;
+        }
+        if self.space && command.starts_with(' ') {
+            return true;
+        }
+
+        let command = command.trim();
+        self.patterns.iter().any(|re| re.is_match(command))
+    }
+}
+
+// `*` is any run of characters, `?` any one, `[...]` a class, and the whole
+// command has to match
+fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    let mut chars = glob.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            '[' => {
+                re.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    re.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        re.push('\\');
+                    }
+                    re.push(c);
+                }
+                re.push(']');
+            }
+            '\\' => match chars.next() {
+                Some(c) => re.push_str(&regex::escape(&c.to_string())),
+                None => re.push_str(r"\\"),
+            },
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    re.push('$');
+    re
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ignores() {
+        let rules = IgnoreRules::new(&IgnoreSettings {
+            patterns: vec!["ls".into(), "cd *".into(), "exit".into(), "[bf]g".into()],
+            regexes: vec!["^git (status|diff)$".into(), "(".into()],
+            ..IgnoreSettings::default()
+        });
+
+        assert!(rules.ignores(" rm -rf secrets"));
+        assert!(rules.ignores("ls"));
+        assert!(rules.ignores("cd /tmp"));
+        assert!(rules.ignores("exit  "));
+        assert!(rules.ignores("fg"));
+        assert!(rules.ignores("git status"));
+        assert!(rules.ignores(""));
+
+        assert!(!rules.ignores("ls -la"));
+        assert!(!rules.ignores("cdrecord x"));
+        assert!(!rules.ignores("git status --short"));
+        assert!(!rules.ignores("cargo build"));
+
+        let rules = IgnoreRules::new(&IgnoreSettings {
+            space: false,
+            ..IgnoreSettings::default()
+        });
+        assert!(!rules.ignores(" rm -rf secrets"));
+    }
+
+    #[test]
+    fn test_glob_to_regex() {
+        assert_eq!(glob_to_regex("cd *"), "^cd .*$");
+        assert_eq!(glob_to_regex("a.b?"), r"^a\.b.$");
+        assert_eq!(glob_to_regex("[!a-c]x"), "^[^a-c]x$");
+        assert_eq!(glob_to_regex(r"\*"), r"^\*$");
+    }
+}
@@ -0,0 +1,589 @@
+use crate::encryption::{EncryptedItem, Key};
+use crate::history_item::HistoryItem;
+use crate::session::Session;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Deserialize;
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::{ConnectOptions, Row};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+
+// nothing we import from a history file knows where it was run
+const UNKNOWN_CWD: &str = "unknown";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportFormat {
+    Auto,
+    Plain,
+    Bash,
+    Zsh,
+    Fish,
+    NuSqlite,
+    Jsonl,
+}
+
+impl ImportFormat {
+    pub const NAMES: &'static [&'static str] =
+        &["auto", "plain", "bash", "zsh", "fish", "nu-sqlite", "jsonl"];
+
+    // guess the format from the first bytes of the file
+    fn detect(bytes: &[u8]) -> Self {
+        if bytes.starts_with(b"SQLite format 3\0") {
+            return ImportFormat::NuSqlite;
+        }
+
+        let first = bytes.split(|b| *b == b'\n').next().unwrap_or_default();
+        let first = String::from_utf8_lossy(first);
+
+        if first.starts_with('{') {
+            ImportFormat::Jsonl
+        } else if first.starts_with("- cmd: ") {
+            ImportFormat::Fish
+        } else if parse_zsh_header(&first).is_some() {
+            ImportFormat::Zsh
+        } else if parse_bash_timestamp(&first).is_some() {
+            ImportFormat::Bash
+        } else {
+            ImportFormat::Plain
+        }
+    }
+}
+
+impl FromStr for ImportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ImportFormat::Auto),
+            // nushell's plaintext history is just one command per line
+            "plain" | "nu" => Ok(ImportFormat::Plain),
+            "bash" => Ok(ImportFormat::Bash),
+            "zsh" => Ok(ImportFormat::Zsh),
+            "fish" => Ok(ImportFormat::Fish),
+            "nu-sqlite" => Ok(ImportFormat::NuSqlite),
+            "jsonl" => Ok(ImportFormat::Jsonl),
+            _ => Err(format!(
+                "unsupported import format {}, expected one of {}",
+                s,
+                ImportFormat::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// What was read out of a history file.
+#[derive(Debug, Default)]
+pub struct Imported {
+    /// Every history entry, oldest first.
+    pub items: Vec<HistoryItem>,
+    /// The sessions the file knew about, only nushell's sqlite history has
+    /// them. Keyed by the session_id the file used, which is what the items
+    /// carry until they're saved under the session_id of their own session.
+    pub sessions: BTreeMap<i64, Session>,
+}
+
+/// Read everything out of `path`. `key` is only needed for an encrypted
+/// jsonl export.
+pub async fn read(
+    path: &Path,
+    format: ImportFormat,
+    key: Option<&Key>,
+) -> Result<Imported, sqlx::Error> {
+    let bytes = std::fs::read(path)?;
+
+    let format = match format {
+        ImportFormat::Auto => ImportFormat::detect(&bytes),
+        f => f,
+    };
+    log::debug!("importing {:?} as {:?}", path, format);
+
+    let now = Utc::now();
+    let items = match format {
+        ImportFormat::NuSqlite => return read_nu_sqlite(path).await,
+        ImportFormat::Jsonl => parse_jsonl(&String::from_utf8_lossy(&bytes), key)?,
+        ImportFormat::Zsh => parse_zsh(&unmetafy(&bytes), now),
+        ImportFormat::Bash => parse_bash(&String::from_utf8_lossy(&bytes), now),
+        ImportFormat::Fish => parse_fish(&String::from_utf8_lossy(&bytes), now),
+        ImportFormat::Plain | ImportFormat::Auto => {
+            parse_plain(&String::from_utf8_lossy(&bytes), now)
+        }
+    };
+
+    Ok(Imported {
+        items,
+        ..Default::default()
+    })
+}
+
+fn item(command: String, duration: i64, timestamp: DateTime<Utc>) -> HistoryItem {
+    HistoryItem::new(
+        None,
+        command,
+        UNKNOWN_CWD.to_string(),
+        duration,
+        -1,
+        None,
+        timestamp,
+    )
+}
+
+fn from_epoch(secs: i64) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(secs, 0).single()
+}
+
+// Files without timestamps keep their order: the last line is a second
+// before `now`, the one before it two seconds, and so on.
+fn fake_timestamp(now: DateTime<Utc>, idx: usize, total: usize) -> DateTime<Utc> {
+    now - chrono::Duration::seconds((total - idx) as i64)
+}
+
+// reedline, and so nushell's plaintext history, writes newlines as `<\n>`
+pub fn parse_plain(contents: &str, now: DateTime<Utc>) -> Vec<HistoryItem> {
+    let lines: Vec<&str> = contents
+        .lines()
+        .map(str::trim_end)
+        .filter(|l| !l.is_empty())
+        .collect();
+
+    lines
+        .iter()
+        .enumerate()
+        .map(|(idx, l)| {
+            let command = l.replace("<\\n>", "\n");
+            item(command, -1, fake_timestamp(now, idx, lines.len()))
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonlLine {
+    Plain(HistoryItem),
+    Encrypted(EncryptedItem),
+}
+
+// what `export --type jsonl` writes, every field comes back as it was. The
+// history_id is read too, but saving hands out a new one. With --encrypt the
+// history_id stays behind.
+pub fn parse_jsonl(contents: &str, key: Option<&Key>) -> Result<Vec<HistoryItem>, sqlx::Error> {
+    contents
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| {
+            match serde_json::from_str(l).map_err(|e| sqlx::Error::Decode(Box::new(e)))? {
+                JsonlLine::Plain(item) => Ok(item),
+                JsonlLine::Encrypted(record) => match key {
+                    Some(key) => key.decrypt(&record).map(|(item, _)| item),
+                    None => Err(sqlx::Error::Configuration(
+                        "this export is encrypted and there's no key, `hiztery key import` the one it was written with"
+                            .into(),
+                    )),
+                },
+            }
+        })
+        .collect()
+}
+
+fn parse_bash_timestamp(line: &str) -> Option<i64> {
+    line.strip_prefix('#')?.trim_end().parse().ok()
+}
+
+// With HISTTIMEFORMAT set bash writes a `#<epoch>` line before every command.
+// Everything up to the next one belongs to that command, which is how
+// multi-line commands end up in the file with `lithist`.
+pub fn parse_bash(contents: &str, now: DateTime<Utc>) -> Vec<HistoryItem> {
+    let mut entries: Vec<(Option<i64>, Vec<&str>)> = vec![];
+    let mut timestamped = false;
+
+    for line in contents.lines() {
+        match parse_bash_timestamp(line) {
+            Some(ts) => {
+                timestamped = true;
+                entries.push((Some(ts), vec![]));
+            }
+            None if line.trim().is_empty() => {}
+            None => match entries.last_mut() {
+                Some((_, lines)) if timestamped => lines.push(line),
+                _ => entries.push((None, vec![line])),
+            },
+        }
+    }
+
+    entries.retain(|(_, lines)| !lines.is_empty());
+
+    let total = entries.len();
+    entries
+        .into_iter()
+        .enumerate()
+        .map(|(idx, (ts, lines))| {
+            let timestamp = ts
+                .and_then(from_epoch)
+                .unwrap_or_else(|| fake_timestamp(now, idx, total));
+            item(lines.join("\n"), -1, timestamp)
+        })
+        .collect()
+}
+
+// `: <epoch>:<seconds>;` in front of every command with EXTENDED_HISTORY
+fn parse_zsh_header(line: &str) -> Option<(i64, i64, &str)> {
+    let rest = line.strip_prefix(": ")?;
+    let (meta, command) = rest.split_once(';')?;
+    let (epoch, duration) = meta.split_once(':')?;
+
+    Some((
+        epoch.trim().parse().ok()?,
+        duration.trim().parse().ok()?,
+        command,
+    ))
+}
+
+// zsh escapes nul and the bytes it uses as tokens (0x83 to 0xa2) as 0x83
+// followed by the byte xor 32, which breaks up plenty of utf-8 sequences
+pub fn unmetafy(bytes: &[u8]) -> String {
+    const META: u8 = 0x83;
+
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut iter = bytes.iter();
+    while let Some(b) = iter.next() {
+        match b {
+            &META => {
+                if let Some(next) = iter.next() {
+                    out.push(next ^ 32);
+                }
+            }
+            b => out.push(*b),
+        }
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// A command that spans several lines is written with a trailing backslash on
+// every line but the last. Lines without a header come from a history file
+// written without EXTENDED_HISTORY and are treated like a plain file.
+pub fn parse_zsh(contents: &str, now: DateTime<Utc>) -> Vec<HistoryItem> {
+    let mut entries: Vec<(Option<(i64, i64)>, String)> = vec![];
+    let mut continued = false;
+
+    for line in contents.lines() {
+        if continued {
+            if let Some((_, command)) = entries.last_mut() {
+                command.push('\n');
+                command.push_str(line);
+            }
+        } else if let Some((epoch, duration, command)) = parse_zsh_header(line) {
+            entries.push((Some((epoch, duration)), command.to_string()));
+        } else if !line.trim().is_empty() {
+            entries.push((None, line.to_string()));
+        } else {
+            continue;
+        }
+
+        // an escaped backslash at the end is a literal one, not a continuation
+        let command = &mut entries.last_mut().unwrap().1;
+        let trailing = command.len() - command.trim_end_matches('\\').len();
+        continued = trailing % 2 == 1;
+        if continued {
+            command.pop();
+        }
+    }
+
+    let total = entries.len();
+    entries
+        .into_iter()
+        .enumerate()
+        .map(
+            |(idx, (meta, command))| match meta.and_then(|(e, d)| Some((from_epoch(e)?, d))) {
+                Some((timestamp, duration)) => item(command, duration * 1_000_000_000, timestamp),
+                None => item(command, -1, fake_timestamp(now, idx, total)),
+            },
+        )
+        .collect()
+}
+
+// fish writes `\\` and `\n` for backslashes and newlines in a command
+fn unescape_fish(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('\\')) => {
+                out.push('\\');
+                chars.next();
+            }
+            ('\\', Some('n')) => {
+                out.push('\n');
+                chars.next();
+            }
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+// fish_history looks like yaml but isn't, so it's parsed by hand:
+//
+// - cmd: cargo build
+//   when: 1600000000
+//   paths:
+//     - Cargo.toml
+pub fn parse_fish(contents: &str, now: DateTime<Utc>) -> Vec<HistoryItem> {
+    let mut entries: Vec<(String, Option<i64>)> = vec![];
+
+    for line in contents.lines() {
+        if let Some(cmd) = line.strip_prefix("- cmd: ") {
+            entries.push((unescape_fish(cmd), None));
+        } else if let Some(when) = line.trim_start().strip_prefix("when: ") {
+            if let Some((_, ts)) = entries.last_mut() {
+                *ts = when.trim().parse().ok();
+            }
+        }
+    }
+
+    let total = entries.len();
+    entries
+        .into_iter()
+        .enumerate()
+        .map(|(idx, (command, ts))| {
+            let timestamp = ts
+                .and_then(from_epoch)
+                .unwrap_or_else(|| fake_timestamp(now, idx, total));
+            item(command, -1, timestamp)
+        })
+        .collect()
+}
+
+// nushell's history.sqlite3 already has everything we keep, the timestamps
+// and durations are in milliseconds. Its session ids mean nothing here, every
+// one of them becomes a nu session that ran from its first to its last command.
+pub async fn read_nu_sqlite(path: &Path) -> Result<Imported, sqlx::Error> {
+    let mut conn = SqliteConnectOptions::new()
+        .filename(path)
+        .read_only(true)
+        .connect()
+        .await?;
+
+    let rows = sqlx::query(
+        "select command_line, start_timestamp, session_id, hostname, cwd, duration_ms, exit_status
+            from history
+            order by id asc",
+    )
+    .fetch_all(&mut conn)
+    .await?;
+
+    let now = Utc::now();
+    let total = rows.len();
+    let items: Vec<HistoryItem> = rows
+        .iter()
+        .enumerate()
+        .map(|(idx, row)| {
+            let timestamp = row
+                .get::<Option<i64>, _>("start_timestamp")
+                .and_then(|ms| Utc.timestamp_millis_opt(ms).single())
+                .unwrap_or_else(|| fake_timestamp(now, idx, total));
+            let duration = row
+                .get::<Option<i64>, _>("duration_ms")
+                .map_or(-1, |ms| ms * 1_000_000);
+
+            let mut item = HistoryItem::new(
+                None,
+                row.get("command_line"),
+                row.get::<Option<String>, _>("cwd")
+                    .unwrap_or_else(|| UNKNOWN_CWD.to_string()),
+                duration,
+                row.get::<Option<i64>, _>("exit_status").unwrap_or(-1),
+                row.get("session_id"),
+                timestamp,
+            );
+            // empty is this machine, like everything else that's imported
+            item.hostname = row.get::<Option<String>, _>("hostname").unwrap_or_default();
+            item
+        })
+        .collect();
+
+    let mut sessions: BTreeMap<i64, Session> = BTreeMap::new();
+    for (row, item) in rows.iter().zip(&items) {
+        let nu_id = match row.get::<Option<i64>, _>("session_id") {
+            Some(id) => id,
+            None => continue,
+        };
+        let ended = item.timestamp + chrono::Duration::nanoseconds(item.duration.max(0));
+
+        let session = sessions.entry(nu_id).or_insert_with(|| {
+            let mut session = Session::new("nu", "");
+            if !item.hostname.is_empty() {
+                session.hostname = item.hostname.clone();
+            }
+            Session {
+                start_time: item.timestamp,
+                end_time: Some(ended),
+                ..session
+            }
+        });
+        session.start_time = session.start_time.min(item.timestamp);
+        session.end_time = session.end_time.max(Some(ended));
+    }
+
+    Ok(Imported { items, sessions })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::session::UNKNOWN_SESSION_ID;
+    use sqlx::Executor;
+
+    fn commands(items: &[HistoryItem]) -> Vec<&str> {
+        items.iter().map(|i| i.command.as_str()).collect()
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            ImportFormat::detect(b"SQLite format 3\0..."),
+            ImportFormat::NuSqlite
+        );
+        assert_eq!(
+            ImportFormat::detect(b"- cmd: ls\n  when: 1\n"),
+            ImportFormat::Fish
+        );
+        assert_eq!(ImportFormat::detect(b": 1:0;ls\n"), ImportFormat::Zsh);
+        assert_eq!(
+            ImportFormat::detect(b"#1600000000\nls\n"),
+            ImportFormat::Bash
+        );
+        assert_eq!(
+            ImportFormat::detect(b"ls\n# a comment\n"),
+            ImportFormat::Plain
+        );
+    }
+
+    #[test]
+    fn test_parse_plain_keeps_order() {
+        let now = Utc::now();
+        let items = parse_plain("ls\n\ncd /tmp\npwd\n", now);
+        assert_eq!(commands(&items), vec!["ls", "cd /tmp", "pwd"]);
+        assert!(items[0].timestamp < items[1].timestamp);
+        assert!(items[2].timestamp < now);
+    }
+
+    #[test]
+    fn test_parse_bash() {
+        let items = parse_bash(
+            "#1600000000\nls -la\n#1600000005\nfor i in 1 2; do\n  echo $i\ndone\n",
+            Utc::now(),
+        );
+        assert_eq!(
+            commands(&items),
+            vec!["ls -la", "for i in 1 2; do\n  echo $i\ndone"]
+        );
+        assert_eq!(items[0].timestamp.timestamp(), 1_600_000_000);
+        assert_eq!(items[1].timestamp.timestamp(), 1_600_000_005);
+        assert_eq!(items[0].duration, -1);
+
+        // without HISTTIMEFORMAT every line is a command
+        let items = parse_bash("ls\npwd\n", Utc::now());
+        assert_eq!(commands(&items), vec!["ls", "pwd"]);
+    }
+
+    #[test]
+    fn test_parse_zsh() {
+        let items = parse_zsh(
+            ": 1600000000:3;cargo build\n: 1600000010:0;echo one \\\\\n: 1600000020:1;for i in 1 2\\\ndo echo $i\\\ndone\n",
+            Utc::now(),
+        );
+        assert_eq!(
+            commands(&items),
+            vec![
+                "cargo build",
+                "echo one \\\\",
+                "for i in 1 2\ndo echo $i\ndone"
+            ]
+        );
+        assert_eq!(items[0].timestamp.timestamp(), 1_600_000_000);
+        assert_eq!(items[0].duration, 3_000_000_000);
+        assert_eq!(items[2].timestamp.timestamp(), 1_600_000_020);
+    }
+
+    #[test]
+    fn test_unmetafy() {
+        // "à" is 0xc3 0xa0, zsh stores the 0xa0 as 0x83 0x80
+        assert_eq!(
+            unmetafy(&[b'e', b'c', b'h', b'o', b' ', 0xc3, 0x83, 0x80]),
+            "echo à"
+        );
+    }
+
+    #[test]
+    fn test_parse_fish() {
+        let items = parse_fish(
+            "- cmd: cargo build\n  when: 1600000000\n  paths:\n    - Cargo.toml\n- cmd: echo a\\nb \\\\\n  when: 1600000005\n",
+            Utc::now(),
+        );
+        assert_eq!(commands(&items), vec!["cargo build", "echo a\nb \\"]);
+        assert_eq!(items[0].timestamp.timestamp(), 1_600_000_000);
+        assert_eq!(items[1].timestamp.timestamp(), 1_600_000_005);
+    }
+
+    #[async_std::test]
+    async fn test_read_nu_sqlite() {
+        let path = std::env::temp_dir().join(format!("hiztery-nu-{}.sqlite3", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut conn = SqliteConnectOptions::new()
+            .filename(&path)
+            .create_if_missing(true)
+            .connect()
+            .await
+            .unwrap();
+        conn.execute(
+            "create table history (
+                id integer primary key autoincrement,
+                command_line text not null,
+                start_timestamp integer,
+                session_id integer,
+                hostname text,
+                cwd text,
+                duration_ms integer,
+                exit_status integer,
+                more_info text
+            );
+            insert into history (command_line, start_timestamp, session_id, hostname, cwd, duration_ms, exit_status)
+                values ('ls', 1600000000123, 7, 'box', '/tmp', 15, 0),
+                       ('cargo test', null, null, null, null, null, null),
+                       ('pwd', 1600000001000, 7, 'box', '/tmp', 2, 0),
+                       ('exit', 1600000002000, 9, 'box', '/tmp', null, 0);",
+        )
+        .await
+        .unwrap();
+        drop(conn);
+
+        let Imported { items, sessions } = read(&path, ImportFormat::Auto, None).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(commands(&items), vec!["ls", "cargo test", "pwd", "exit"]);
+        assert_eq!(items[0].timestamp.timestamp_millis(), 1_600_000_000_123);
+        assert_eq!(items[0].duration, 15_000_000);
+        assert_eq!(items[0].exit_status, 0);
+        assert_eq!(items[0].session_id, 7);
+        assert_eq!(items[0].cwd, "/tmp");
+        assert_eq!(items[0].hostname, "box");
+        assert_eq!(items[1].hostname, "");
+        assert_eq!(items[1].cwd, UNKNOWN_CWD);
+        assert_eq!(items[1].exit_status, -1);
+        assert_eq!(items[1].session_id, UNKNOWN_SESSION_ID);
+
+        assert_eq!(sessions.keys().collect::<Vec<_>>(), vec![&7, &9]);
+        assert_eq!(sessions[&7].shell, "nu");
+        assert_eq!(sessions[&7].hostname, "box");
+        assert_eq!(sessions[&7].start_time, items[0].timestamp);
+        assert_eq!(
+            sessions[&7].end_time.unwrap().timestamp_millis(),
+            1_600_000_001_002
+        );
+        assert_eq!(sessions[&9].end_time, Some(items[3].timestamp));
+        assert_ne!(sessions[&7].uuid, sessions[&9].uuid);
+    }
+}
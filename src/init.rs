@@ -0,0 +1,42 @@
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Nu,
+}
+
+impl Shell {
+    pub const NAMES: &'static [&'static str] = &["bash", "zsh", "fish", "nu"];
+
+    // the hook code that records every command with start/end and binds
+    // ctrl-r to the interactive search
+    pub fn script(self) -> &'static str {
+        match self {
+            Shell::Bash => include_str!("shell/hiztery.bash"),
+            Shell::Zsh => include_str!("shell/hiztery.zsh"),
+            Shell::Fish => include_str!("shell/hiztery.fish"),
+            Shell::Nu => include_str!("shell/hiztery.nu"),
+        }
+    }
+}
+
+impl FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            "nu" | "nushell" => Ok(Shell::Nu),
+            _ => Err(format!(
+                "unsupported shell {}, expected one of {}",
+                s,
+                Shell::NAMES.join(", ")
+            )),
+        }
+    }
+}
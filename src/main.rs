@@ -1,28 +1,73 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 
+pub mod check;
 pub mod database;
+pub mod encryption;
+pub mod export;
+pub mod filter;
+pub mod fuzzy;
 pub mod history_item;
+pub mod ignore;
+pub mod import;
+pub mod init;
+pub mod migrations;
+pub mod output;
+pub mod perf;
+pub mod prune;
+pub mod scrub;
+pub mod session;
+pub mod settings;
+pub mod stats;
+pub mod sync;
+pub mod tui;
 
 use crate::history_item::HistoryItem;
-use chrono::{DateTime, NaiveDate};
+use chrono::{DateTime, NaiveDate, TimeZone};
 use database::{Database, SearchMode, Sqlite};
-use lazy_static::lazy_static;
+use encryption::Key;
+use export::ExportFormat;
+use filter::{CwdFilter, ExitFilter, HistoryFilter};
+use ignore::IgnoreRules;
+use import::ImportFormat;
+use init::Shell;
 use log::debug;
+use output::OutputFormat;
+use perf::{Perf, Period};
+use prune::RetentionSettings;
+use scrub::Scrubber;
+use session::Session;
+use settings::Settings;
 use simplelog::*;
-use std::convert::TryInto;
 use std::io::BufRead;
-use std::io::{self, BufReader, Read};
+use std::io::{self, BufReader, Read, Write};
 use std::io::{Seek, SeekFrom};
-use std::{fs::File, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Component, Path, PathBuf},
+};
 use structopt::StructOpt;
 
-lazy_static! {
-    static ref PID: i64 = std::process::id().into();
-}
-
 #[derive(StructOpt)]
 struct Args {
+    /// Path to the history database, overrides $HIZTERY_DB and the config file
+    #[structopt(long = "db", global = true, parse(from_os_str))]
+    db: Option<PathBuf>,
+    /// Don't log to the terminal, the shell hooks run everything this way
+    #[structopt(long = "quiet", global = true)]
+    quiet: bool,
+    /// Log debug output to the terminal instead of only warnings
+    #[structopt(short = "v", long = "verbose", global = true)]
+    verbose: bool,
+    /// How results are printed to stdout
+    #[structopt(
+        long = "format",
+        global = true,
+        default_value = "table",
+        possible_values = OutputFormat::NAMES
+    )]
+    format: OutputFormat,
     #[structopt(subcommand)]
     cmd: Option<HizteryCmd>,
 }
@@ -35,36 +80,182 @@ enum HizteryCmd {
         history_item: String,
         #[structopt(short = "r", long = "rows_to_insert")]
         rows_to_insert: i64,
+        #[structopt(short = "s", long = "session", env = "HIZTERY_SESSION")]
+        session: Option<String>,
     },
-    Update {
+    /// Record a command that is about to run and print its history id
+    Start {
+        #[structopt(short = "c", long = "command", allow_hyphen_values = true)]
+        command: String,
+        #[structopt(long = "cwd")]
+        cwd: Option<String>,
+        /// The uuid `sessions start` printed, or a session_id
+        #[structopt(short = "s", long = "session", env = "HIZTERY_SESSION")]
+        session: Option<String>,
+    },
+    /// Fill in the exit status and duration of a command recorded with start
+    End {
         #[structopt(short = "i", long = "id")]
         history_id: i64,
-        // #[structopt(short = "u", long = "update_text")]
-        // history_item: String,
+        #[structopt(short = "e", long = "exit", allow_hyphen_values = true)]
+        exit_status: i64,
+        /// Duration in nanoseconds, measured from the start timestamp if not given
+        #[structopt(short = "d", long = "duration")]
+        duration: Option<i64>,
+        /// CPU time the command spent in user space, in nanoseconds
+        #[structopt(long = "user-time")]
+        user_time: Option<i64>,
+        /// CPU time the command spent in the kernel, in nanoseconds
+        #[structopt(long = "sys-time")]
+        sys_time: Option<i64>,
+        /// The most memory the command had resident, in KiB
+        #[structopt(long = "max-rss")]
+        max_rss: Option<i64>,
     },
-    Delete {
+    /// Change a saved command, only the fields given are touched
+    Update {
         #[structopt(short = "i", long = "id")]
         history_id: i64,
+        #[structopt(short = "c", long = "command", allow_hyphen_values = true)]
+        command: Option<String>,
+        #[structopt(long = "cwd")]
+        cwd: Option<String>,
+        #[structopt(short = "e", long = "exit", allow_hyphen_values = true)]
+        exit_status: Option<i64>,
+        /// e.g. 500ms, 2s or 5m
+        #[structopt(short = "d", long = "duration", parse(try_from_str = parse_duration))]
+        duration: Option<i64>,
+        /// When it ran, rfc 3339 or local yyyy-mm-dd hh:mm:ss
+        #[structopt(short = "t", long = "timestamp")]
+        timestamp: Option<String>,
+        /// Edit the command in $VISUAL or $EDITOR
+        #[structopt(long = "edit", conflicts_with = "command")]
+        edit: bool,
+    },
+    /// Delete one command by id, or every command a query and the filters
+    /// match, after showing them
+    Delete {
+        #[structopt(
+            short = "i",
+            long = "id",
+            conflicts_with_all = &[
+                "query", "hostname", "username", "cwd", "cwd-tree", "success", "failed",
+                "exit", "session", "since", "until", "min-duration", "max-duration", "offset",
+            ]
+        )]
+        history_id: Option<i64>,
+        #[structopt(short = "q", long = "query")]
+        query: Option<String>,
+        /// p prefix, f full text, z fuzzy or r regex
+        #[structopt(
+            short = "m",
+            long = "mode",
+            default_value = "f",
+            possible_values = SearchMode::NAMES
+        )]
+        search_mode: SearchMode,
+        /// Don't ask before deleting
+        #[structopt(short = "y", long = "yes")]
+        yes: bool,
+        #[structopt(flatten)]
+        filter: FilterArgs,
     },
+    #[structopt(alias = "list")]
     Select {
         #[structopt(short = "m", long = "max")]
         max: Option<usize>,
         #[structopt(short = "u", long = "unique")]
         unique: bool,
+        #[structopt(flatten)]
+        filter: FilterArgs,
     },
     Import {
-        #[structopt(short = "f", long = "file", name = "file path")]
-        nushell_history_filepath: String,
+        #[structopt(short = "f", long = "file", name = "file path", parse(from_os_str))]
+        history_filepath: PathBuf,
+        /// What kind of history file it is, auto looks at the contents
+        #[structopt(
+            short = "t",
+            long = "type",
+            default_value = "auto",
+            possible_values = ImportFormat::NAMES
+        )]
+        import_format: ImportFormat,
+    },
+    /// Write history out for backups or other tools, jsonl and csv keep every field
+    Export {
+        #[structopt(
+            long = "type",
+            default_value = "jsonl",
+            possible_values = ExportFormat::NAMES
+        )]
+        export_format: ExportFormat,
+        #[structopt(short = "f", long = "from")]
+        from_date: Option<String>,
+        #[structopt(short = "t", long = "to")]
+        to_date: Option<String>,
+        /// Write to a file instead of stdout
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output_path: Option<PathBuf>,
+        /// Encrypt every item with the key next to the database, jsonl only
+        #[structopt(long = "encrypt")]
+        encrypt: bool,
+    },
+    /// Serve this database to `hiztery sync` clients
+    Server {
+        #[structopt(short = "l", long = "listen", default_value = sync::DEFAULT_ADDRESS)]
+        address: String,
+    },
+    /// Push new history to the sync server and pull what other machines pushed
+    Sync {
+        /// host:port of the server, defaults to sync_address from the config
+        #[structopt(short = "s", long = "server")]
+        address: Option<String>,
     },
     Search {
-        #[structopt(short = "m", long = "mode")]
-        search_mode: String,
+        /// p prefix, f full text, z fuzzy or r regex
+        #[structopt(
+            short = "m",
+            long = "mode",
+            default_value = "f",
+            possible_values = SearchMode::NAMES
+        )]
+        search_mode: SearchMode,
         #[structopt(short = "l", long = "limit")]
         limit: Option<i64>,
-        #[structopt(short = "q", long = "query")]
+        #[structopt(short = "q", long = "query", default_value = "")]
         query: String,
+        /// Pick a result and print it to stdout for the shell to pick up
+        #[structopt(short = "i", long = "interactive")]
+        interactive: bool,
+        #[structopt(flatten)]
+        filter: FilterArgs,
     },
     Count {},
+    /// Most used, slowest and flakiest commands, and when and where they ran
+    Stats {
+        /// How many rows every list gets
+        #[structopt(short = "n", long = "top", default_value = "10")]
+        top: i64,
+        /// Commands run fewer times than this aren't listed as slow or flaky
+        #[structopt(long = "min-runs", default_value = "2")]
+        min_runs: i64,
+        #[structopt(flatten)]
+        filter: FilterArgs,
+    },
+    /// How one command's wall time, cpu time and memory changed over time
+    Perf {
+        /// The command line, exactly as it was run
+        #[structopt(short = "c", long = "command")]
+        command: String,
+        /// Average the runs per period, or list every run
+        #[structopt(long = "by", default_value = "day", possible_values = Period::NAMES)]
+        by: Period,
+        /// Flag a period whose average wall time grew by more than this many percent
+        #[structopt(long = "threshold", default_value = "20")]
+        threshold: f64,
+        #[structopt(flatten)]
+        filter: FilterArgs,
+    },
     Last {},
     First {},
     Load {
@@ -72,10 +263,12 @@ enum HizteryCmd {
         id: String,
     },
     Range {
-        #[structopt(short = "f", long = "from")]
+        #[structopt(short = "f", long = "from", conflicts_with_all = &["since", "until"])]
         from_date: String,
-        #[structopt(short = "t", long = "to")]
+        #[structopt(short = "t", long = "to", conflicts_with_all = &["since", "until"])]
         to_date: String,
+        #[structopt(flatten)]
+        filter: FilterArgs,
     },
     Before {
         #[structopt(short = "f", long = "from")]
@@ -84,6 +277,176 @@ enum HizteryCmd {
         count: i64,
     },
     All {},
+    /// Print the hook code for a shell, e.g. eval "$(hiztery init bash)"
+    Init {
+        #[structopt(possible_values = Shell::NAMES)]
+        shell: Shell,
+    },
+    /// Redact or drop secrets already in the database with the scrub patterns
+    Scrub {
+        /// Only count what would change
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+    },
+    /// Find rows whose parent row is gone and a full text index that's out of
+    /// step, and repair them
+    Check {
+        /// Only report what would be repaired
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+    },
+    /// Drop old history by the [retention] rules in the config, then compact
+    /// the database
+    Prune {
+        /// Only count what would go
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+        /// Drop everything older than this many days
+        #[structopt(long = "days")]
+        days: Option<i64>,
+        /// Keep at most this many commands, the newest ones
+        #[structopt(long = "max-rows")]
+        max_rows: Option<i64>,
+        /// Drop failed commands older than this many days
+        #[structopt(long = "failed-days")]
+        failed_days: Option<i64>,
+        /// Keep only the newest copy of every command
+        #[structopt(long = "dedupe")]
+        dedupe: bool,
+    },
+    /// Share the key that encrypts synced and exported history
+    Key {
+        #[structopt(subcommand)]
+        cmd: KeyCmd,
+    },
+    /// List the terminals history was recorded in, newest first
+    Sessions {
+        #[structopt(subcommand)]
+        cmd: Option<SessionsCmd>,
+    },
+}
+
+#[derive(StructOpt)]
+enum SessionsCmd {
+    List {
+        #[structopt(short = "l", long = "limit")]
+        limit: Option<i64>,
+    },
+    /// Record a new session and print its uuid, the shell hooks export it
+    /// as $HIZTERY_SESSION
+    Start {
+        #[structopt(long = "shell", default_value = "")]
+        shell: String,
+        #[structopt(long = "tty", default_value = "")]
+        tty: String,
+    },
+    /// Record that a session's shell exited
+    End {
+        #[structopt(short = "s", long = "session", env = "HIZTERY_SESSION")]
+        session: String,
+    },
+}
+
+#[derive(StructOpt)]
+struct FilterArgs {
+    /// Only history from this machine
+    #[structopt(long = "host")]
+    hostname: Option<String>,
+    /// Only history from this user
+    #[structopt(long = "user")]
+    username: Option<String>,
+    /// Only commands run in this directory, `--cwd .` is the current one
+    #[structopt(long = "cwd", conflicts_with = "cwd-tree")]
+    cwd: Option<String>,
+    /// Only commands run in this directory or below it
+    #[structopt(long = "cwd-tree")]
+    cwd_tree: Option<String>,
+    /// `dir` ranks commands run in the current directory, then in its
+    /// parents, before the rest
+    #[structopt(long = "rank", default_value = "recent", possible_values = &["recent", "dir"])]
+    rank: String,
+    /// Only commands that exited with 0
+    #[structopt(long = "success", conflicts_with_all = &["failed", "exit"])]
+    success: bool,
+    /// Only commands that exited with anything but 0
+    #[structopt(long = "failed", conflicts_with = "exit")]
+    failed: bool,
+    /// Only commands that exited with this code
+    #[structopt(long = "exit")]
+    exit: Option<i64>,
+    /// Only one terminal's history, by uuid or session_id. select lists it
+    /// oldest first, the way it was typed
+    #[structopt(short = "s", long = "session")]
+    session: Option<String>,
+    /// Only commands run on or after this day, yyyy-mm-dd
+    #[structopt(long = "since")]
+    since: Option<String>,
+    /// Only commands run on or before this day, yyyy-mm-dd
+    #[structopt(long = "until")]
+    until: Option<String>,
+    /// Only commands that ran at least this long, e.g. 500ms, 2s or 5m
+    #[structopt(long = "min-duration", parse(try_from_str = parse_duration))]
+    min_duration: Option<i64>,
+    /// Only commands that ran at most this long
+    #[structopt(long = "max-duration", parse(try_from_str = parse_duration))]
+    max_duration: Option<i64>,
+    /// Skip this many results
+    #[structopt(long = "offset")]
+    offset: Option<i64>,
+}
+
+impl FilterArgs {
+    async fn filter(self, sqlite: &Sqlite) -> Result<HistoryFilter, sqlx::Error> {
+        let session_id = match self.session {
+            Some(key) => find_session(sqlite, &key).await?.session_id,
+            None => None,
+        };
+        let exit = if self.success {
+            Some(ExitFilter::Success)
+        } else if self.failed {
+            Some(ExitFilter::Failure)
+        } else {
+            self.exit.map(ExitFilter::Code)
+        };
+        let cwd = match (self.cwd, self.cwd_tree) {
+            (Some(dir), _) => Some(CwdFilter::Exact(resolve_dir(&dir))),
+            (None, Some(dir)) => Some(CwdFilter::Tree(resolve_dir(&dir))),
+            (None, None) => None,
+        };
+        let near = match self.rank.as_str() {
+            "dir" => Some(current_dir()),
+            _ => None,
+        };
+        let until = self.until.as_deref().map(parse_end_date).transpose()?;
+
+        Ok(HistoryFilter {
+            cwd,
+            exit,
+            session_id,
+            hostname: self.hostname,
+            username: self.username,
+            since: self.since.as_deref().map(parse_date).transpose()?,
+            until,
+            min_duration: self.min_duration,
+            max_duration: self.max_duration,
+            near,
+            offset: self.offset,
+            ..Default::default()
+        })
+    }
+}
+
+#[derive(StructOpt)]
+enum KeyCmd {
+    /// Print the key, creating one if there isn't one yet
+    Export {},
+    /// Use a key printed by `key export` on another machine
+    Import {
+        key: String,
+        /// Replace an existing key, anything encrypted with it becomes unreadable
+        #[structopt(long = "force")]
+        force: bool,
+    },
 }
 
 #[derive(Debug, sqlx::FromRow)]
@@ -101,210 +464,496 @@ struct HistoryTable {
 #[async_std::main]
 #[paw::main]
 async fn main(args: Args) -> anyhow::Result<()> {
-    CombinedLogger::init(vec![
-        TermLogger::new(
-            LevelFilter::Debug,
+    let settings = Settings::load()?;
+    let db_path = settings.db_path(args.db.clone());
+
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![];
+    if !args.quiet {
+        loggers.push(TermLogger::new(
+            if args.verbose {
+                LevelFilter::Debug
+            } else {
+                LevelFilter::Warn
+            },
             Config::default(),
-            TerminalMode::Mixed,
+            // stdout is reserved for output the shell hooks read back
+            TerminalMode::Stderr,
             ColorChoice::Auto,
-        ),
-        WriteLogger::new(
-            LevelFilter::Debug,
-            Config::default(),
-            File::create("my_rust_binary.log").unwrap(),
-        ),
-    ])
-    .unwrap();
+        ));
+    }
+    // the hooks run us from every directory, so the log goes next to the
+    // database instead of into the current one
+    if args.verbose || settings.log_file {
+        if let Some(log_file) = open_log_file(&db_path) {
+            loggers.push(WriteLogger::new(
+                if args.verbose {
+                    LevelFilter::Debug
+                } else {
+                    LevelFilter::Warn
+                },
+                Config::default(),
+                log_file,
+            ));
+        }
+    }
+    CombinedLogger::init(loggers).unwrap();
 
     debug!("starting main");
+
     // let result = first_attempt(args).await?;
-    let results = second_attempt(args).await?;
+    second_attempt(args, db_path, settings).await?;
 
-    Ok(results)
+    Ok(())
 }
 
-async fn second_attempt(args: Args) -> Result<(), sqlx::Error> {
+async fn second_attempt(
+    args: Args,
+    db_path: PathBuf,
+    settings: Settings,
+) -> Result<(), sqlx::Error> {
     debug!("starting second_attempt");
     // let pool = SqlitePool::connect("sqlite:hiztery.db?mode=rwc").await?;
     // initialize_db(&pool).await?;
 
-    let db_path =
-        PathBuf::from("C:\\Users\\dschroeder\\source\\repos\\forks\\sql\\hiztery\\hizzy.db");
+    // init only prints the hook code, it has no business creating a database
+    if let Some(HizteryCmd::Init { shell }) = &args.cmd {
+        print!("{}", shell.script());
+        return Ok(());
+    }
+
+    let key_path = encryption::key_path(&db_path);
+    match &args.cmd {
+        Some(HizteryCmd::Key {
+            cmd: KeyCmd::Export {},
+        }) => {
+            println!("{}", Key::load_or_create(&key_path)?.encode());
+            return Ok(());
+        }
+        Some(HizteryCmd::Key {
+            cmd: KeyCmd::Import { key, force },
+        }) => {
+            let key = Key::decode(key)?;
+            // importing the key we already have is fine, replacing it is not
+            let existing = Key::load(&key_path)?.map(|k| k.encode());
+            if existing.is_some_and(|e| e != key.encode()) && !force {
+                return Err(sqlx::Error::Configuration(
+                    format!("{:?} already exists, pass --force to replace it", key_path).into(),
+                ));
+            }
+            key.save(&key_path)?;
+            return Ok(());
+        }
+        _ => {}
+    }
+
     // let sqlite = Sqlite::new(db_path).await?;
     let mut sqlite = match Sqlite::new(db_path).await {
-        Ok(r) => r,
+        Ok(r) => r
+            .with_scrubber(Scrubber::new(&settings.scrub))
+            .with_ignore_rules(IgnoreRules::new(&settings.ignore)),
         // Err(e) => anyhow::bail!("unexpected error: {}", e),
         Err(e) => return Err(e),
     };
 
+    let format = args.format;
+
     match args.cmd {
         Some(HizteryCmd::Insert {
             history_item,
             rows_to_insert,
+            session,
         }) => {
             // cargo run -- insert --text "happy birthday" --rows_to_insert 5
-            debug!("Insert with {} {}", &history_item, rows_to_insert);
+            debug!("Inserting {} rows", rows_to_insert);
+            let session_id = session_id(&mut sqlite, session).await?;
             for row in 0..rows_to_insert {
                 let hi = HistoryItem::new(
                     None,
                     history_item.clone(),
-                    "i_give_up".to_string(),
+                    current_dir(),
                     0,
                     0,
-                    Some(*PID),
+                    Some(session_id),
                     chrono::Utc::now(),
                 );
 
-                let result = sqlite.save(&hi).await?;
+                sqlite.save(&hi).await?;
                 // match result {
                 //     Ok(r) => r,
                 //     Err(e) => return Err(e),
                 // }
             }
         }
-        Some(HizteryCmd::Update {
-            history_id,
-            // history_item,
+        Some(HizteryCmd::Start {
+            command,
+            cwd,
+            session,
         }) => {
-            // cargo run -- update -i 1
-            debug!("Update with id: {}", history_id);
+            // cargo run -- start -c "cargo build" -s $HIZTERY_SESSION
+            debug!("Starting a command in session [{:?}]", &session);
+            let session_id = session_id(&mut sqlite, session).await?;
+            // duration and exit status stay -1 until the matching end comes in
             let hi = HistoryItem::new(
-                Some(history_id),
-                "some | updated | command".to_string(),
-                "i_updated".to_string(),
-                1,
-                0,
-                Some(*PID),
+                None,
+                command,
+                cwd.unwrap_or_else(current_dir),
+                -1,
+                -1,
+                Some(session_id),
                 chrono::Utc::now(),
             );
 
-            let result = sqlite.update(&hi).await?;
-            // match result {
-            //     Ok(r) => r,
-            //     Err(e) => return Err(e),
-            // }
+            // nothing to print for a command that was filtered out, the
+            // hooks skip the end call when there's no id
+            if let Some(history_id) = sqlite.save(&hi).await? {
+                println!("{}", history_id);
+            }
         }
-        Some(HizteryCmd::Delete { history_id }) => {
+        Some(HizteryCmd::End {
+            history_id,
+            exit_status,
+            duration,
+            user_time,
+            sys_time,
+            max_rss,
+        }) => {
+            // cargo run -- end -i 42 -e 0 --user-time 120000000 --sys-time 30000000
+            debug!(
+                "Ending history item: [{}] with exit status: [{}]",
+                history_id, exit_status
+            );
+            let mut hi = sqlite.load(&history_id.to_string()).await?;
+            hi.exit_status = exit_status;
+            hi.duration = duration.unwrap_or_else(|| {
+                (chrono::Utc::now() - hi.timestamp)
+                    .num_nanoseconds()
+                    .unwrap_or(i64::MAX)
+            });
+
+            sqlite.update(&hi).await?;
+            sqlite
+                .save_perf(&Perf {
+                    history_id,
+                    wall_time: hi.duration,
+                    user_time,
+                    sys_time,
+                    max_rss,
+                })
+                .await?;
+        }
+        Some(HizteryCmd::Update {
+            history_id,
+            command,
+            cwd,
+            exit_status,
+            duration,
+            timestamp,
+            edit,
+        }) => {
+            // cargo run -- update -i 1 -c "cargo test" --exit 0
+            // cargo run -- update -i 1 --edit
+            debug!("Update with id: {}", history_id);
+            let mut item = find_item(&sqlite, history_id).await?;
+            if let Some(command) = command {
+                item.command = command;
+            }
+            if edit {
+                item.command = edit_command(&item.command)?;
+            }
+            if let Some(cwd) = cwd {
+                item.cwd = resolve_dir(&cwd);
+            }
+            if let Some(exit_status) = exit_status {
+                item.exit_status = exit_status;
+            }
+            if let Some(duration) = duration {
+                item.duration = duration;
+            }
+            if let Some(timestamp) = timestamp {
+                item.timestamp = parse_timestamp(&timestamp)?;
+            }
+
+            sqlite.update(&item).await?;
+            // what was saved, the scrubber may have redacted some of it
+            let item = find_item(&sqlite, history_id).await?;
+            output::print(&[item], format)?;
+        }
+        Some(HizteryCmd::Delete {
+            history_id,
+            query,
+            search_mode,
+            yes,
+            filter,
+        }) => {
             // cargo run -- delete -i 3
-            debug!("Deleting history item: [{}]", history_id);
-            let res = sqlite.delete_history_item(history_id).await?;
+            // cargo run -- delete -q hunter2 -m r --since 2023-01-01 --yes
+            let filter = filter.filter(&sqlite).await?;
+            let items = match (history_id, query) {
+                (Some(id), _) => vec![find_item(&sqlite, id).await?],
+                (None, Some(query)) => sqlite.matches(search_mode, &query, &filter).await?,
+                // without a query the filters alone have to narrow it down,
+                // a bare delete would empty the database. --rank only sorts.
+                (None, None)
+                    if HistoryFilter {
+                        near: None,
+                        ..filter.clone()
+                    } != HistoryFilter::default() =>
+                {
+                    sqlite.query(&filter).await?
+                }
+                (None, None) => {
+                    return Err(sqlx::Error::Configuration(
+                        "delete needs an --id, a --query or a filter".into(),
+                    ))
+                }
+            };
+            // stdout only has the commands, what's said about them goes
+            // to stderr like the question
+            if items.is_empty() {
+                eprintln!("nothing to delete");
+                return Ok(());
+            }
+
+            output::print(&items, format)?;
+            let question = match items.len() {
+                1 => "delete this command?".to_string(),
+                n => format!("delete these {} commands?", n),
+            };
+            if !yes && !confirm(&question)? {
+                return Ok(());
+            }
+
+            let ids: Vec<i64> = items.iter().filter_map(|h| h.history_id).collect();
+            debug!("Deleting history items: {:?}", ids);
+            let res = sqlite.delete_history_items(&ids).await?;
             debug!("Deleted row count: [{}]", res);
+            eprintln!("deleted {}", res);
         }
-        Some(HizteryCmd::Select { max, unique }) => {
-            // cargo run -- select -m 5 -u
+        Some(HizteryCmd::Select {
+            max,
+            unique,
+            filter,
+        }) => {
+            // cargo run -- select -m 5 -u --failed --cwd-tree ~/src
             debug!("Selecting max: [{:?}] with unique: [{}]", max, unique);
-            let output = sqlite.list(max, unique).await?;
-            for (idx, item) in output.iter().enumerate() {
-                debug!("ItemNum: [{}] Row: [{:?}]", idx, item);
+            let filter = HistoryFilter {
+                unique,
+                limit: max.map(|m| m as i64),
+                ..filter.filter(&sqlite).await?
+            };
+            let mut output = sqlite.query(&filter).await?;
+            // a session reads like the terminal did, the last few oldest first
+            if filter.session_id.is_some() {
+                output.reverse();
             }
+            debug!("Found {} items", output.len());
+            output::print(&output, format)?;
         }
         Some(HizteryCmd::Import {
-            nushell_history_filepath,
+            history_filepath,
+            import_format,
         }) => {
-            debug!("Import with file: {}", &nushell_history_filepath);
-            let file = File::open(nushell_history_filepath);
-            let mut reader = BufReader::new(file.unwrap());
-            let lines = count_lines(&mut reader)?;
-            debug!("Lines: {}", lines);
-
-            let mut history_vec = vec![];
+            // cargo run -- import -f ~/.zsh_history
+            debug!("Import with file: {:?}", &history_filepath);
+            let key = Key::load(&key_path)?;
+            let imported = import::read(&history_filepath, import_format, key.as_ref()).await?;
+            let before = sqlite.history_count().await?;
 
-            for (idx, line) in reader.lines().enumerate() {
-                // println!("{}", line?);
-                let time = chrono::Utc::now();
-                let offset = chrono::Duration::seconds(idx.try_into().unwrap());
-                let time = time - offset;
-
-                // self.counter += 1;
-
-                history_vec.push(HistoryItem::new(
-                    None,
-                    line?.trim_end().to_string(),
-                    String::from("unknown"),
-                    -1,
-                    -1,
-                    None,
-                    time,
-                ));
+            // the file's session ids to the ones its sessions got here
+            let mut session_ids = HashMap::new();
+            for (id, session) in &imported.sessions {
+                session_ids.insert(*id, sqlite.save_session(session).await?);
+            }
+            let mut history_vec = imported.items;
+            for item in history_vec.iter_mut() {
+                if let Some(session_id) = session_ids.get(&item.session_id) {
+                    item.session_id = *session_id;
+                }
             }
 
             debug!("Preparing for save_bulk");
-            let result = sqlite.save_bulk(&history_vec).await?;
-            let cnt = sqlite.history_count().await?;
-            //  {
-            //     Ok(c) => c,
-            //     _ => 0i64,
-            // };
+            sqlite.save_bulk(&history_vec).await?;
+            // entries that are already there are skipped, so count what's new
+            let cnt = sqlite.history_count().await? - before;
             debug!("Imported [{}] history entries", cnt);
+            println!("{}", cnt);
+        }
+        Some(HizteryCmd::Export {
+            export_format,
+            from_date,
+            to_date,
+            output_path,
+            encrypt,
+        }) => {
+            // cargo run -- export --type zsh -f "2021-07-21" -o zsh_history
+            debug!(
+                "Export as {:?} between [{:?}] and [{:?}]",
+                export_format, &from_date, &to_date
+            );
+            let from = match from_date {
+                Some(d) => parse_date(&d)?,
+                None => chrono::Utc.timestamp_nanos(0),
+            };
+            let to = match to_date {
+                Some(d) => parse_end_date(&d)?,
+                None => chrono::Utc.timestamp_nanos(i64::MAX),
+            };
+            // everything that can go wrong before the output file is touched
+            let key = match (encrypt, export_format) {
+                (false, _) => None,
+                (true, ExportFormat::Jsonl) => Some(Key::load_or_create(&key_path)?),
+                (true, _) => {
+                    return Err(sqlx::Error::Configuration(
+                        "--encrypt only works with --type jsonl".into(),
+                    ))
+                }
+            };
+            let filter = HistoryFilter {
+                since: Some(from),
+                until: Some(to),
+                oldest_first: true,
+                ..Default::default()
+            };
+            let result = sqlite.query(&filter).await?;
+            debug!("Exporting {} items", result.len());
+
+            let mut out: Box<dyn io::Write> = match output_path {
+                Some(path) => Box::new(io::BufWriter::new(File::create(path)?)),
+                None => Box::new(io::stdout().lock()),
+            };
+            match key {
+                Some(key) => export::write_encrypted(&result, &key, &mut out)?,
+                None => export::write(&result, export_format, &mut out)?,
+            }
+        }
+        Some(HizteryCmd::Server { address }) => {
+            // cargo run -- server -l 0.0.0.0:4040
+            let listener = async_std::net::TcpListener::bind(&address).await?;
+            eprintln!(
+                "hiztery sync server listening on {}",
+                listener.local_addr()?
+            );
+            sync::serve(sqlite, listener).await?;
+        }
+        Some(HizteryCmd::Sync { address }) => {
+            // cargo run -- sync -s 192.168.1.10:4040
+            let address = address
+                .or_else(|| settings.sync_address.clone())
+                .unwrap_or_else(|| sync::DEFAULT_ADDRESS.to_string());
+            let key = Key::load_or_create(&key_path)?;
+            let stats = sync::sync(&mut sqlite, &address, &key).await?;
+            println!("pushed {}, pulled {}", stats.pushed, stats.pulled);
+            if stats.skipped > 0 {
+                eprintln!(
+                    "skipped {} records that don't decrypt with this key",
+                    stats.skipped
+                );
+            }
         }
         Some(HizteryCmd::Search {
             search_mode,
             limit,
             query,
+            interactive,
+            filter,
         }) => {
             // cargo run -- search -m "p" -q "code"
-            debug!(
-                "Searching with phrase: {}, limit: {:?}, mode: {}",
-                &query, limit, &search_mode
-            );
-            let s_mode = match search_mode.as_ref() {
-                "p" => SearchMode::Prefix,
-                "f" => SearchMode::FullText,
-                "z" => SearchMode::Fuzzy,
-                _ => SearchMode::FullText,
-            };
+            debug!("Searching with limit: {:?}, mode: {}", limit, &search_mode);
 
-            let result = sqlite.search(limit, s_mode, &query).await;
-            match result {
-                Ok(r) => {
-                    debug!("Found {} hits", r.len());
-                    for (idx, hit) in r.iter().enumerate() {
-                        debug!("Hit # [{}] History: [{}]", idx + 1, hit.command);
-                    }
+            let filter = HistoryFilter {
+                limit,
+                ..filter.filter(&sqlite).await?
+            };
+            if interactive {
+                if let Some(command) =
+                    tui::pick(&sqlite, search_mode, limit, query, filter, current_dir()).await?
+                {
+                    println!("{}", command);
                 }
-                _ => debug!("No hits found for phrase: {}", &query),
+                return Ok(());
             }
+
+            let result = sqlite.search(search_mode, &query, &filter).await?;
+            debug!("Found {} hits", result.len());
+            output::print(&result, format)?;
         }
         Some(HizteryCmd::Count {}) => {
             // cargo run -- count
             debug!("Counting history items.");
             let result = sqlite.history_count().await?;
             debug!("Found [{}] history items.", result);
+            println!("{}", result);
+        }
+        Some(HizteryCmd::Stats {
+            top,
+            min_runs,
+            filter,
+        }) => {
+            // cargo run -- stats -n 5 --cwd-tree .
+            if format != OutputFormat::Table && format != OutputFormat::Json {
+                return Err(sqlx::Error::Configuration(
+                    "stats only prints with --format table or json".into(),
+                ));
+            }
+            let filter = filter.filter(&sqlite).await?;
+            let result = sqlite.stats(&filter, top, min_runs).await?;
+            debug!("Stats over {} history items", result.total);
+            stats::print(&result, format)?;
+        }
+        Some(HizteryCmd::Perf {
+            command,
+            by,
+            threshold,
+            filter,
+        }) => {
+            // cargo run -- perf -c "cargo build" --by week --success
+            let filter = filter.filter(&sqlite).await?;
+            let mut result = sqlite.perf(&command, by, &filter).await?;
+            perf::mark_regressions(&mut result, threshold);
+            debug!("Found {} periods", result.len());
+            output::print(&result, format)?;
         }
         Some(HizteryCmd::Last {}) => {
             // cargo run -- last
             debug!("Looking for the last history item.");
             let result = sqlite.last().await?;
-            debug!("Found [{:?}] history items.", result);
+            debug!("Found history item [{:?}].", result.history_id);
+            output::print(&[result], format)?;
         }
         Some(HizteryCmd::First {}) => {
             // cargo run -- first
             debug!("Looking for the first history item.");
             let result = sqlite.first().await?;
-            debug!("Found [{:?}] history items.", result);
+            debug!("Found history item [{:?}].", result.history_id);
+            output::print(&[result], format)?;
         }
         Some(HizteryCmd::Load { id }) => {
             // cargo run -- load -i 2800
             debug!("Looking for history item [{}].", &id);
             let result = sqlite.load(&id).await?;
-            debug!("Found [{:?}] history items.", result);
+            debug!("Found history item [{:?}].", result.history_id);
+            output::print(&[result], format)?;
         }
-        Some(HizteryCmd::Range { from_date, to_date }) => {
+        Some(HizteryCmd::Range {
+            from_date,
+            to_date,
+            filter,
+        }) => {
             // cargo run -- range -f "2021-07-21" -t "2021-07-25"
             debug!(
                 "Looking for history item between [{}] and [{}].",
                 &from_date, &to_date
             );
-            let f = NaiveDate::parse_from_str(&from_date, "%Y-%m-%d").unwrap();
-            let t = NaiveDate::parse_from_str(&to_date, "%Y-%m-%d").unwrap();
-            let f_utc = DateTime::<chrono::Utc>::from_utc(f.and_hms(0, 0, 0), chrono::Utc);
-            let t_utc = DateTime::<chrono::Utc>::from_utc(t.and_hms(0, 0, 0), chrono::Utc);
-            let result = sqlite.range(f_utc, t_utc).await?;
+            let filter = HistoryFilter {
+                since: Some(parse_date(&from_date)?),
+                until: Some(parse_end_date(&to_date)?),
+                oldest_first: true,
+                ..filter.filter(&sqlite).await?
+            };
+            let result = sqlite.query(&filter).await?;
 
             debug!("Found {} hits", result.len());
-            for (idx, hit) in result.iter().enumerate() {
-                debug!("Hit # [{}] History: [{:?}]", idx + 1, hit);
-            }
+            output::print(&result, format)?;
         }
         Some(HizteryCmd::Before { from_date, count }) => {
             // cargo run -- before -f "2021-07-21" -c 25
@@ -313,29 +962,310 @@ async fn second_attempt(args: Args) -> Result<(), sqlx::Error> {
                 &from_date, count,
             );
             let f = NaiveDate::parse_from_str(&from_date, "%Y-%m-%d").unwrap();
-            let f_utc = DateTime::<chrono::Utc>::from_utc(f.and_hms(0, 0, 0), chrono::Utc);
+            let f_utc =
+                DateTime::<chrono::Utc>::from_utc(f.and_hms_opt(0, 0, 0).unwrap(), chrono::Utc);
             let result = sqlite.before(f_utc, count).await?;
 
             debug!("Found {} hits", result.len());
-            for (idx, hit) in result.iter().enumerate() {
-                debug!("Hit # [{}] History: [{:?}]", idx + 1, hit);
-            }
+            output::print(&result, format)?;
         }
         Some(HizteryCmd::All {}) => {
             // cargo run -- last
             debug!("Looking for all the history items.");
             let result = sqlite.query_history("select * from history_items").await?;
             debug!("Found {} hits", result.len());
-            for (idx, hit) in result.iter().enumerate() {
-                debug!("Hit # [{}] History: [{:?}]", idx + 1, hit);
+            output::print(&result, format)?;
+        }
+        Some(HizteryCmd::Scrub { dry_run }) => {
+            let (redacted, dropped) = sqlite.scrub(dry_run).await?;
+            debug!("Scrubbed [{}] redacted [{}] dropped", redacted, dropped);
+            println!("redacted {}, dropped {}", redacted, dropped);
+        }
+        Some(HizteryCmd::Check { dry_run }) => {
+            let problems = sqlite.check(dry_run).await?;
+            debug!("Found {} kinds of problems", problems.len());
+            if problems.is_empty() && format == OutputFormat::Table {
+                println!("no problems found");
+            } else {
+                output::print(&problems, format)?;
+            }
+        }
+        Some(HizteryCmd::Prune {
+            dry_run,
+            days,
+            max_rows,
+            failed_days,
+            dedupe,
+        }) => {
+            // cargo run -- prune --days 365 --dedupe --dry-run
+            // flags win over the config, rule by rule
+            let retention = RetentionSettings {
+                days: days.or(settings.retention.days),
+                max_rows: max_rows.or(settings.retention.max_rows),
+                failed_days: failed_days.or(settings.retention.failed_days),
+                dedupe: dedupe || settings.retention.dedupe,
+            };
+            if retention.is_empty() {
+                return Err(sqlx::Error::Configuration(
+                    "nothing to prune, set [retention] in the config or pass --days, --max-rows, --failed-days or --dedupe".into(),
+                ));
+            }
+
+            let pruned = sqlite.prune(&retention, dry_run).await?;
+            if !dry_run {
+                sqlite.compact().await?;
             }
+            output::print(&pruned, format)?;
         }
-        None => {}
+        Some(HizteryCmd::Sessions { cmd }) => match cmd {
+            None => output::print(&sqlite.sessions(None).await?, format)?,
+            Some(SessionsCmd::List { limit }) => {
+                output::print(&sqlite.sessions(limit).await?, format)?
+            }
+            Some(SessionsCmd::Start { shell, tty }) => {
+                // cargo run -- sessions start --shell bash --tty $(tty)
+                let session = Session::new(&shell, &tty);
+                sqlite.save_session(&session).await?;
+                println!("{}", session.uuid);
+            }
+            Some(SessionsCmd::End { session }) => {
+                let session = find_session(&sqlite, &session).await?;
+                sqlite
+                    .end_session(session.session_id.unwrap(), chrono::Utc::now())
+                    .await?;
+            }
+        },
+        Some(HizteryCmd::Init { .. }) | Some(HizteryCmd::Key { .. }) | None => {}
     }
 
     Ok(())
 }
 
+async fn find_session(sqlite: &Sqlite, key: &str) -> Result<Session, sqlx::Error> {
+    sqlite
+        .session(key)
+        .await?
+        .ok_or_else(|| sqlx::Error::Configuration(format!("there's no session {}", key).into()))
+}
+
+async fn find_item(sqlite: &Sqlite, id: i64) -> Result<HistoryItem, sqlx::Error> {
+    match sqlite.load(&id.to_string()).await {
+        Err(sqlx::Error::RowNotFound) => Err(sqlx::Error::Configuration(
+            format!("there's no command {}", id).into(),
+        )),
+        res => res,
+    }
+}
+
+// The session_id to save a command under. A uuid that isn't known yet (the
+// database was moved or recreated under a running shell) starts a session of
+// its own, and without one the command goes to the unknown session.
+async fn session_id(sqlite: &mut Sqlite, key: Option<String>) -> Result<i64, sqlx::Error> {
+    let key = match key {
+        Some(key) if !key.is_empty() => key,
+        _ => return Ok(session::UNKNOWN_SESSION_ID),
+    };
+
+    if let Some(session) = sqlite.session(&key).await? {
+        return Ok(session.session_id.unwrap());
+    }
+    // a plain number is what hooks from before sessions pass ($$), saving
+    // under it creates the session
+    if let Ok(id) = key.parse::<i64>() {
+        return Ok(id);
+    }
+
+    let mut session = Session::new("", "");
+    session.uuid = key;
+    sqlite.save_session(&session).await
+}
+
+// ask on stderr so the answer isn't mixed into what's printed, anything but
+// y or yes (or no terminal to answer on) is a no
+fn confirm(question: &str) -> io::Result<bool> {
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+// appended to, every shell writes to the same one
+fn open_log_file(db_path: &Path) -> Option<File> {
+    let log_path = db_path.with_file_name("hiztery.log");
+    if let Some(dir) = log_path.parent() {
+        std::fs::create_dir_all(dir).ok()?;
+    }
+
+    let mut opts = std::fs::OpenOptions::new();
+    opts.create(true).append(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+
+    opts.open(log_path).ok()
+}
+
+// $PWD when it's still where we are, that's what the hooks record and it
+// keeps the symlinks the user cd'ed through
+fn current_dir() -> String {
+    let cwd = match std::env::current_dir() {
+        Ok(dir) => dir,
+        Err(_) => return String::from("unknown"),
+    };
+
+    match std::env::var_os("PWD").map(PathBuf::from) {
+        Some(pwd) if pwd.is_absolute() && pwd.canonicalize().ok() == cwd.canonicalize().ok() => {
+            pwd.to_string_lossy().into_owned()
+        }
+        _ => cwd.to_string_lossy().into_owned(),
+    }
+}
+
+// A directory to match the cwd column against. Relative ones are taken from
+// the current directory, and . and .. are worked out without following
+// symlinks, the same way the shell's $PWD does it.
+fn resolve_dir(dir: &str) -> String {
+    let path = Path::new(dir);
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        Path::new(&current_dir()).join(path)
+    };
+
+    let mut resolved = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            c => resolved.push(c),
+        }
+    }
+
+    resolved.to_string_lossy().into_owned()
+}
+
+// the same yyyy-mm-dd dates range and before take, as midnight utc
+fn parse_date(date: &str) -> Result<DateTime<chrono::Utc>, sqlx::Error> {
+    let d = NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|e| {
+        sqlx::Error::Configuration(
+            format!("invalid date {}, expected yyyy-mm-dd: {}", date, e).into(),
+        )
+    })?;
+
+    Ok(DateTime::<chrono::Utc>::from_utc(
+        d.and_hms_opt(0, 0, 0).unwrap(),
+        chrono::Utc,
+    ))
+}
+
+// parse_date for the end of a range, which takes in the whole day, up to
+// the last nanosecond before midnight
+fn parse_end_date(date: &str) -> Result<DateTime<chrono::Utc>, sqlx::Error> {
+    Ok(parse_date(date)? + chrono::Duration::days(1) - chrono::Duration::nanoseconds(1))
+}
+
+// a point in time for update, rfc 3339 or a local date and time, anything
+// parse_date takes is midnight
+fn parse_timestamp(timestamp: &str) -> Result<DateTime<chrono::Utc>, sqlx::Error> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(timestamp) {
+        return Ok(t.with_timezone(&chrono::Utc));
+    }
+    if let Ok(t) = chrono::NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S") {
+        return chrono::Local
+            .from_local_datetime(&t)
+            .earliest()
+            .map(|t| t.with_timezone(&chrono::Utc))
+            .ok_or_else(|| {
+                sqlx::Error::Configuration(
+                    format!("{} doesn't exist in the local time zone", timestamp).into(),
+                )
+            });
+    }
+
+    parse_date(timestamp)
+}
+
+// The command after the user is done with it in their editor. An empty file
+// is a change of mind, not an empty command.
+fn edit_command(command: &str) -> Result<String, sqlx::Error> {
+    let editor = ["VISUAL", "EDITOR"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_string());
+    // $EDITOR is often a command with flags like code --wait. Split the way
+    // a shell would, without needing one, there's no sh on windows.
+    let words = shell_words::split(&editor).map_err(|e| {
+        sqlx::Error::Configuration(format!("can't make sense of {}: {}", editor, e).into())
+    })?;
+    let (program, args) = words
+        .split_first()
+        .ok_or_else(|| sqlx::Error::Configuration("no editor to edit with".into()))?;
+
+    // the command may hold a secret: a name nobody can guess or have put a
+    // symlink at, that only we can read
+    let path = std::env::temp_dir().join(format!("hiztery-{}.sh", session::new_uuid()));
+    let mut opts = std::fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+    writeln!(opts.open(&path)?, "{}", command)?;
+
+    let status = std::process::Command::new(program)
+        .args(args)
+        .arg(&path)
+        .status();
+    let edited = std::fs::read_to_string(&path);
+    std::fs::remove_file(&path).ok();
+
+    if !status?.success() {
+        return Err(sqlx::Error::Configuration(
+            format!("{} exited with an error, nothing was updated", editor).into(),
+        ));
+    }
+    let edited = edited?.trim_end_matches('\n').to_string();
+    if edited.trim().is_empty() {
+        return Err(sqlx::Error::Configuration(
+            "the command is empty, nothing was updated".into(),
+        ));
+    }
+
+    Ok(edited)
+}
+
+// a duration in nanoseconds, like the ones that are saved, from 250ms, 2s,
+// 1.5m or 1h. A bare number is seconds.
+fn parse_duration(s: &str) -> Result<i64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: f64 = num
+        .parse()
+        .map_err(|_| format!("invalid duration {}, expected e.g. 500ms or 2s", s))?;
+    let scale = match unit.trim() {
+        "ns" => 1.0,
+        "us" => 1e3,
+        "ms" => 1e6,
+        "" | "s" => 1e9,
+        "m" => 60e9,
+        "h" => 3600e9,
+        unit => {
+            return Err(format!(
+                "unknown duration unit {}, use ns, us, ms, s, m or h",
+                unit
+            ))
+        }
+    };
+
+    Ok((num * scale) as i64)
+}
+
 fn count_lines(buf: &mut BufReader<impl Read + Seek>) -> Result<usize, io::Error> {
     let lines = buf.lines().count();
     buf.seek(SeekFrom::Start(0))?;
@@ -0,0 +1,368 @@
+use log::debug;
+use sqlx::sqlite::{SqliteConnection, SqlitePool};
+
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+// Migrations are applied in order and each one runs in its own transaction.
+// Never edit a migration that has shipped, add a new one to the end instead.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create history_items and performance_items",
+        // the IF NOT EXISTS clauses let databases created before schema_version
+        // existed adopt this migration without losing anything
+        //TODO: add run_count
+        //TODO: maybe change command to command_line and then
+        // add command with only the command and parameters as
+        // a separate column in order to do interesting queries
+        sql: r#"
+        CREATE TABLE IF NOT EXISTS history_items (
+            history_id   INTEGER PRIMARY KEY NOT NULL,
+            timestamp    INTEGER NOT NULL,
+            duration     INTEGER NOT NULL,
+            exit_status  INTEGER NOT NULL,
+            command      TEXT NOT NULL,
+            cwd          TEXT NOT NULL,
+            session_id   INTEGER NOT NULL,
+
+            UNIQUE(timestamp, cwd, command)
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_history_timestamp on history_items(timestamp);
+        CREATE INDEX IF NOT EXISTS idx_history_command on history_items(command);
+
+        CREATE TABLE IF NOT EXISTS performance_items (
+            perf_id     INTEGER NOT NULL PRIMARY KEY,
+            metrics     FLOAT NOT NULL,
+            history_id  INTEGER NOT NULL
+            REFERENCES history_items(history_id) ON DELETE CASCADE ON UPDATE CASCADE
+        );"#,
+    },
+    Migration {
+        version: 2,
+        description: "add history_fts full text index over command",
+        // history_fts is an external content table, it only stores the index
+        // and the triggers keep it in step with history_items
+        sql: r#"
+        CREATE VIRTUAL TABLE history_fts USING fts5(
+            command,
+            content='history_items',
+            content_rowid='history_id'
+        );
+
+        CREATE TRIGGER history_items_fts_insert AFTER INSERT ON history_items BEGIN
+            INSERT INTO history_fts(rowid, command) VALUES (new.history_id, new.command);
+        END;
+
+        CREATE TRIGGER history_items_fts_delete AFTER DELETE ON history_items BEGIN
+            INSERT INTO history_fts(history_fts, rowid, command)
+                VALUES ('delete', old.history_id, old.command);
+        END;
+
+        CREATE TRIGGER history_items_fts_update AFTER UPDATE OF command ON history_items BEGIN
+            INSERT INTO history_fts(history_fts, rowid, command)
+                VALUES ('delete', old.history_id, old.command);
+            INSERT INTO history_fts(rowid, command) VALUES (new.history_id, new.command);
+        END;
+
+        INSERT INTO history_fts(history_fts) VALUES ('rebuild');"#,
+    },
+    Migration {
+        version: 3,
+        description: "add sync_state for per-server high-water marks",
+        // last_pushed is our own history_id, last_pulled is the server's
+        sql: r#"
+        CREATE TABLE sync_state (
+            host         TEXT PRIMARY KEY NOT NULL,
+            last_pushed  INTEGER NOT NULL DEFAULT 0,
+            last_pulled  INTEGER NOT NULL DEFAULT 0,
+            synced_at    INTEGER NOT NULL
+        );"#,
+    },
+    Migration {
+        version: 4,
+        description: "add sync_records for the encrypted items a sync server keeps",
+        // the server can't read these, record_key is an HMAC that only tells
+        // copies of the same item apart
+        sql: r#"
+        CREATE TABLE sync_records (
+            record_id   INTEGER PRIMARY KEY NOT NULL,
+            record_key  TEXT NOT NULL UNIQUE,
+            version     INTEGER NOT NULL,
+            nonce       TEXT NOT NULL,
+            data        TEXT NOT NULL
+        );"#,
+    },
+    Migration {
+        version: 5,
+        description: "add sessions, one row per terminal",
+        // Session 0 is for history that doesn't know where it came from. Every
+        // session_id already in use (mostly shell pids) becomes a session of
+        // its own, spanning its first to its last command, with a random
+        // version 4 uuid since there's nothing to build a v7 one from.
+        sql: r#"
+        CREATE TABLE sessions (
+            session_id  INTEGER PRIMARY KEY NOT NULL,
+            uuid        TEXT NOT NULL UNIQUE,
+            hostname    TEXT NOT NULL DEFAULT '',
+            username    TEXT NOT NULL DEFAULT '',
+            shell       TEXT NOT NULL DEFAULT '',
+            tty         TEXT NOT NULL DEFAULT '',
+            start_time  INTEGER NOT NULL,
+            end_time    INTEGER
+        );
+
+        CREATE INDEX idx_sessions_start_time on sessions(start_time);
+        CREATE INDEX idx_history_session on history_items(session_id, timestamp);
+
+        INSERT INTO sessions(session_id, uuid, start_time)
+            VALUES (0, '00000000-0000-0000-0000-000000000000', 0);
+
+        INSERT OR IGNORE INTO sessions(session_id, uuid, start_time, end_time)
+            SELECT session_id,
+                lower(hex(randomblob(4))) || '-' || lower(hex(randomblob(2))) || '-4' ||
+                substr(lower(hex(randomblob(2))), 2) || '-' ||
+                substr('89ab', 1 + abs(random() % 4), 1) ||
+                substr(lower(hex(randomblob(2))), 2) || '-' || lower(hex(randomblob(6))),
+                min(timestamp),
+                max(timestamp)
+            FROM history_items
+            GROUP BY session_id;"#,
+    },
+    Migration {
+        version: 6,
+        description: "add hostname and username to history_items",
+        // what came before only has the session to go by, anything else stays
+        // empty for unknown
+        sql: r#"
+        ALTER TABLE history_items ADD COLUMN hostname TEXT NOT NULL DEFAULT '';
+        ALTER TABLE history_items ADD COLUMN username TEXT NOT NULL DEFAULT '';
+
+        UPDATE history_items SET
+            hostname = coalesce((select hostname from sessions s where s.session_id = history_items.session_id), ''),
+            username = coalesce((select username from sessions s where s.session_id = history_items.session_id), '');
+
+        CREATE INDEX idx_history_hostname on history_items(hostname, username);"#,
+    },
+    Migration {
+        version: 7,
+        description: "record wall, cpu and memory per command in performance_items",
+        // nothing ever wrote the old metrics column, so there's nothing to
+        // carry over. Times are nanoseconds like duration, max_rss is KiB like
+        // getrusage reports it, and whatever the shell couldn't measure is null.
+        sql: r#"
+        DROP TABLE IF EXISTS performance_items;
+
+        CREATE TABLE performance_items (
+            perf_id     INTEGER PRIMARY KEY NOT NULL,
+            history_id  INTEGER NOT NULL UNIQUE
+                REFERENCES history_items(history_id) ON DELETE CASCADE ON UPDATE CASCADE,
+            wall_time   INTEGER NOT NULL,
+            user_time   INTEGER,
+            sys_time    INTEGER,
+            max_rss     INTEGER
+        );"#,
+    },
+    Migration {
+        version: 8,
+        description: "remember which commands were pulled from which sync server",
+        // so they aren't pushed straight back to the server they came from
+        sql: r#"
+        CREATE TABLE sync_pulled (
+            history_id  INTEGER NOT NULL
+                REFERENCES history_items(history_id) ON DELETE CASCADE ON UPDATE CASCADE,
+            host        TEXT NOT NULL,
+
+            PRIMARY KEY(history_id, host)
+        );"#,
+    },
+    Migration {
+        version: 9,
+        description: "add sync_server, the id this database has as a sync server",
+        // clients key their marks on it, whatever address they reach us by.
+        // A new database is a new server, its record ids start over.
+        sql: r#"
+        CREATE TABLE sync_server (
+            id         INTEGER PRIMARY KEY NOT NULL CHECK (id = 1),
+            server_id  TEXT NOT NULL
+        );"#,
+    },
+];
+
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+pub async fn current_version(conn: &mut SqliteConnection) -> Result<i64, sqlx::Error> {
+    let res: (Option<i64>,) = sqlx::query_as("select max(version) from schema_version")
+        .fetch_one(conn)
+        .await?;
+
+    Ok(res.0.unwrap_or(0))
+}
+
+pub async fn run(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    let mut conn = pool.acquire().await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version      INTEGER PRIMARY KEY NOT NULL,
+            description  TEXT NOT NULL,
+            applied_at   INTEGER NOT NULL
+        )",
+    )
+    .execute(&mut conn)
+    .await?;
+
+    let latest = latest_version();
+
+    for migration in MIGRATIONS {
+        // BEGIN IMMEDIATE takes the write lock up front so two processes
+        // opening the same database can't both decide to apply a migration
+        sqlx::query("BEGIN IMMEDIATE").execute(&mut conn).await?;
+
+        match apply(&mut conn, migration, latest).await {
+            Ok(_) => {
+                sqlx::query("COMMIT").execute(&mut conn).await?;
+            }
+            Err(e) => {
+                sqlx::query("ROLLBACK").execute(&mut conn).await?;
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn apply(
+    conn: &mut SqliteConnection,
+    migration: &Migration,
+    latest: i64,
+) -> Result<(), sqlx::Error> {
+    let version = current_version(conn).await?;
+
+    if version > latest {
+        return Err(sqlx::Error::Configuration(
+            format!(
+                "database schema version {} is newer than the latest version {} this hiztery knows about, please upgrade hiztery",
+                version, latest
+            )
+            .into(),
+        ));
+    }
+
+    if migration.version <= version {
+        return Ok(());
+    }
+
+    debug!(
+        "applying migration {}: {}",
+        migration.version, migration.description
+    );
+
+    sqlx::query(migration.sql).execute(&mut *conn).await?;
+    sqlx::query("insert into schema_version(version, description, applied_at) values(?1, ?2, ?3)")
+        .bind(migration.version)
+        .bind(migration.description)
+        .bind(chrono::Utc::now().timestamp_nanos())
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+    use std::str::FromStr;
+
+    async fn memory_pool() -> SqlitePool {
+        let opts = SqliteConnectOptions::from_str("sqlite::memory:").unwrap();
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(opts)
+            .await
+            .unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_migrations_apply_once() {
+        let pool = memory_pool().await;
+        run(&pool).await.unwrap();
+        run(&pool).await.unwrap();
+
+        let mut conn = pool.acquire().await.unwrap();
+        assert_eq!(current_version(&mut conn).await.unwrap(), latest_version());
+
+        let applied: (i64,) = sqlx::query_as("select count(1) from schema_version")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(applied.0, MIGRATIONS.len() as i64);
+    }
+
+    #[async_std::test]
+    async fn test_migrations_adopt_unversioned_database() {
+        let pool = memory_pool().await;
+        sqlx::query(
+            "CREATE TABLE history_items (
+                history_id   INTEGER PRIMARY KEY NOT NULL,
+                timestamp    INTEGER NOT NULL,
+                duration     INTEGER NOT NULL,
+                exit_status  INTEGER NOT NULL,
+                command      TEXT NOT NULL,
+                cwd          TEXT NOT NULL,
+                session_id   INTEGER NOT NULL,
+
+                UNIQUE(timestamp, cwd, command)
+            );
+            insert into history_items(timestamp, duration, exit_status, command, cwd, session_id)
+                values(1, 0, 0, 'ls', '/tmp', 1);",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        run(&pool).await.unwrap();
+
+        let count: (i64,) = sqlx::query_as("select count(1) from history_items")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count.0, 1);
+
+        // the old session_id became a session, next to the unknown one
+        let sessions: Vec<(i64, String, i64)> =
+            sqlx::query_as("select session_id, uuid, start_time from sessions order by session_id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[1].0, 1);
+        assert_eq!(sessions[1].1.len(), 36);
+        assert_eq!(sessions[1].2, 1);
+    }
+
+    #[async_std::test]
+    async fn test_migrations_refuse_newer_database() {
+        let pool = memory_pool().await;
+        run(&pool).await.unwrap();
+
+        sqlx::query("insert into schema_version(version, description, applied_at) values(?1, 'from the future', 0)")
+            .bind(latest_version() + 1)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let err = run(&pool).await.unwrap_err();
+        assert!(err.to_string().contains("newer than the latest version"));
+    }
+}
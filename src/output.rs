@@ -0,0 +1,347 @@
+use crate::history_item::HistoryItem;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+    Plain,
+    Nuon,
+}
+
+impl OutputFormat {
+    pub const NAMES: &'static [&'static str] = &["table", "json", "csv", "plain", "nuon"];
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            "plain" => Ok(OutputFormat::Plain),
+            "nuon" => Ok(OutputFormat::Nuon),
+            _ => Err(format!(
+                "unsupported format {}, expected one of {}",
+                s,
+                OutputFormat::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+// A single cell. Keeping the type around lets every format pick its own
+// representation, e.g. a duration is 1.5s in a table but 1500000000ns in nuon.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Text(String),
+    Date(DateTime<Utc>),
+    // nanoseconds, negative means unknown
+    Duration(i64),
+    Null,
+}
+
+/// Anything that can be printed as rows. JSON comes straight from serde, the
+/// other formats are built from `headers` and `cells`.
+pub trait Tabular: Serialize {
+    fn headers() -> Vec<&'static str>;
+    fn cells(&self) -> Vec<Value>;
+
+    // what --format plain prints for one row
+    fn plain(&self) -> String {
+        self.cells()
+            .iter()
+            .map(Value::raw)
+            .collect::<Vec<_>>()
+            .join("\t")
+    }
+}
+
+impl Tabular for HistoryItem {
+    fn headers() -> Vec<&'static str> {
+        vec![
+            "history_id",
+            "timestamp",
+            "duration",
+            "exit_status",
+            "command",
+            "cwd",
+            "session_id",
+            "hostname",
+            "username",
+        ]
+    }
+
+    fn cells(&self) -> Vec<Value> {
+        vec![
+            self.history_id.map_or(Value::Null, Value::Int),
+            Value::Date(self.timestamp),
+            Value::Duration(self.duration),
+            Value::Int(self.exit_status),
+            Value::Text(self.command.clone()),
+            Value::Text(self.cwd.clone()),
+            Value::Int(self.session_id),
+            Value::Text(self.hostname.clone()),
+            Value::Text(self.username.clone()),
+        ]
+    }
+
+    // plain is meant for piping into other tools, so just the command
+    fn plain(&self) -> String {
+        self.command.clone()
+    }
+}
+
+impl Value {
+    // the machine readable form, used by csv and plain
+    fn raw(&self) -> String {
+        match self {
+            Value::Int(i) | Value::Duration(i) => i.to_string(),
+            Value::Float(f) => f.to_string(),
+            Value::Text(s) => s.clone(),
+            Value::Date(d) => d.to_rfc3339(),
+            Value::Null => String::new(),
+        }
+    }
+
+    // the human readable form, used by table
+    fn human(&self) -> String {
+        match self {
+            Value::Float(f) => format!("{:.2}", f),
+            Value::Text(s) => s.replace('\n', " "),
+            Value::Date(d) => d
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+            Value::Duration(d) => format_duration(*d),
+            _ => self.raw(),
+        }
+    }
+
+    fn nuon(&self) -> String {
+        match self {
+            Value::Int(i) => i.to_string(),
+            Value::Float(f) if f.is_finite() => format!("{:?}", f),
+            Value::Float(_) => "null".to_string(),
+            // a json string is also a valid nuon string
+            Value::Text(s) => serde_json::to_string(s).unwrap_or_default(),
+            Value::Date(d) => d.to_rfc3339(),
+            Value::Duration(d) if *d >= 0 => format!("{}ns", d),
+            Value::Duration(_) | Value::Null => "null".to_string(),
+        }
+    }
+}
+
+pub fn print<T: Tabular>(items: &[T], format: OutputFormat) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    out.write_all(render(items, format)?.as_bytes())?;
+    out.flush()
+}
+
+pub fn render<T: Tabular>(items: &[T], format: OutputFormat) -> io::Result<String> {
+    let rendered = match format {
+        OutputFormat::Table => render_table(items),
+        OutputFormat::Json => {
+            let mut json = serde_json::to_string_pretty(items)?;
+            json.push('\n');
+            json
+        }
+        OutputFormat::Csv => render_csv(items)?,
+        OutputFormat::Plain => items.iter().map(|i| i.plain() + "\n").collect(),
+        OutputFormat::Nuon => render_nuon(items),
+    };
+
+    Ok(rendered)
+}
+
+fn render_table<T: Tabular>(items: &[T]) -> String {
+    let headers = T::headers();
+    let rows: Vec<Vec<String>> = items
+        .iter()
+        .map(|i| i.cells().iter().map(Value::human).collect())
+        .collect();
+
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let line = |cells: Vec<String>| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        padded.join("  ").trim_end().to_string() + "\n"
+    };
+
+    let mut table = line(headers.iter().map(|h| h.to_string()).collect());
+    table += &line(widths.iter().map(|w| "-".repeat(*w)).collect());
+    for row in rows {
+        table += &line(row);
+    }
+
+    table
+}
+
+fn render_csv<T: Tabular>(items: &[T]) -> io::Result<String> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(T::headers())?;
+    for item in items {
+        writer.write_record(item.cells().iter().map(Value::raw))?;
+    }
+
+    let bytes = writer
+        .into_inner()
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// nuon's table literal: [[col, col]; [val, val], [val, val]]
+fn render_nuon<T: Tabular>(items: &[T]) -> String {
+    if items.is_empty() {
+        return "[]\n".to_string();
+    }
+
+    let rows: Vec<String> = items
+        .iter()
+        .map(|i| {
+            let cells: Vec<String> = i.cells().iter().map(Value::nuon).collect();
+            format!("[{}]", cells.join(", "))
+        })
+        .collect();
+
+    format!("[[{}]; {}]\n", T::headers().join(", "), rows.join(", "))
+}
+
+pub fn format_exit_status(exit_status: i64) -> String {
+    // -1 means the command is still running or never reported back
+    if exit_status < 0 {
+        "-".to_string()
+    } else {
+        exit_status.to_string()
+    }
+}
+
+pub fn format_age(age: chrono::Duration) -> String {
+    let secs = age.num_seconds().max(0);
+
+    match secs {
+        s if s < 60 => format!("{}s", s),
+        s if s < 60 * 60 => format!("{}m", s / 60),
+        s if s < 60 * 60 * 24 => format!("{}h", s / (60 * 60)),
+        s if s < 60 * 60 * 24 * 7 => format!("{}d", s / (60 * 60 * 24)),
+        s if s < 60 * 60 * 24 * 365 => format!("{}w", s / (60 * 60 * 24 * 7)),
+        s => format!("{}y", s / (60 * 60 * 24 * 365)),
+    }
+}
+
+// durations are stored in nanoseconds, negative means unknown
+pub fn format_duration(nanos: i64) -> String {
+    if nanos < 0 {
+        return "-".to_string();
+    }
+
+    let micros = nanos / 1_000;
+    let millis = nanos / 1_000_000;
+    let secs = millis / 1_000;
+
+    match secs {
+        _ if millis == 0 => format!("{}µs", micros),
+        0 => format!("{}ms", millis),
+        s if s < 60 => format!("{:.1}s", millis as f64 / 1_000.0),
+        s if s < 60 * 60 => format!("{}m{}s", s / 60, s % 60),
+        s => format!("{}h{}m", s / (60 * 60), (s / 60) % 60),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn items() -> Vec<HistoryItem> {
+        vec![HistoryItem::new(
+            Some(7),
+            "echo \"hi\", there".to_string(),
+            "/tmp".to_string(),
+            1_500_000_000,
+            0,
+            Some(42),
+            Utc.timestamp_opt(1_600_000_000, 0).unwrap(),
+        )]
+    }
+
+    #[test]
+    fn test_render_csv() {
+        let csv = render(&items(), OutputFormat::Csv).unwrap();
+        assert_eq!(
+            csv,
+            "history_id,timestamp,duration,exit_status,command,cwd,session_id,hostname,username\n\
+             7,2020-09-13T12:26:40+00:00,1500000000,0,\"echo \"\"hi\"\", there\",/tmp,42,,\n"
+        );
+    }
+
+    #[test]
+    fn test_render_nuon() {
+        let nuon = render(&items(), OutputFormat::Nuon).unwrap();
+        assert_eq!(
+            nuon,
+            "[[history_id, timestamp, duration, exit_status, command, cwd, session_id, hostname, username]; \
+             [7, 2020-09-13T12:26:40+00:00, 1500000000ns, 0, \"echo \\\"hi\\\", there\", \"/tmp\", 42, \"\", \"\"]]\n"
+        );
+        assert_eq!(
+            render::<HistoryItem>(&[], OutputFormat::Nuon).unwrap(),
+            "[]\n"
+        );
+    }
+
+    #[test]
+    fn test_render_json_and_plain() {
+        let json = render(&items(), OutputFormat::Json).unwrap();
+        let parsed: Vec<HistoryItem> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed[0].command, "echo \"hi\", there");
+        assert_eq!(parsed[0].timestamp, items()[0].timestamp);
+
+        let plain = render(&items(), OutputFormat::Plain).unwrap();
+        assert_eq!(plain, "echo \"hi\", there\n");
+    }
+
+    #[test]
+    fn test_render_table() {
+        let table = render(&items(), OutputFormat::Table).unwrap();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("history_id  timestamp"));
+        assert!(lines[2].contains("1.5s"));
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(-1), "-");
+        assert_eq!(format_duration(12_000), "12µs");
+        assert_eq!(format_duration(12_000_000), "12ms");
+        assert_eq!(format_duration(1_500_000_000), "1.5s");
+        assert_eq!(format_duration(125_000_000_000), "2m5s");
+        assert_eq!(format_duration(3_900_000_000_000), "1h5m");
+    }
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(chrono::Duration::seconds(5)), "5s");
+        assert_eq!(format_age(chrono::Duration::minutes(3)), "3m");
+        assert_eq!(format_age(chrono::Duration::hours(30)), "1d");
+        assert_eq!(format_age(chrono::Duration::days(400)), "1y");
+    }
+}
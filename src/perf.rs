@@ -0,0 +1,149 @@
+use crate::output::{Tabular, Value};
+use serde::Serialize;
+use std::str::FromStr;
+
+/// What one run of a command cost, a row of performance_items. Times are
+/// nanoseconds, max_rss is KiB, None when the shell couldn't measure it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Perf {
+    pub history_id: i64,
+    pub wall_time: i64,
+    pub user_time: Option<i64>,
+    pub sys_time: Option<i64>,
+    pub max_rss: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+    Run,
+    Day,
+    Week,
+    Month,
+}
+
+impl Period {
+    pub const NAMES: &'static [&'static str] = &["run", "day", "week", "month"];
+
+    // what the runs are grouped by, in local time
+    pub fn sql(self, table: &str) -> String {
+        let time = format!("{}.timestamp / 1000000000, 'unixepoch', 'localtime'", table);
+        let format = match self {
+            Period::Run => "%Y-%m-%d %H:%M:%S",
+            Period::Day => "%Y-%m-%d",
+            // the iso week, which sqlite has no %G and %V for. A week's
+            // Thursday is always in the year the week counts towards.
+            Period::Week => {
+                let thursday = format!("date({}, '-3 days', 'weekday 4')", time);
+                return format!(
+                    "strftime('%Y', {t}) || printf('-W%02d', (strftime('%j', {t}) - 1) / 7 + 1)",
+                    t = thursday
+                );
+            }
+            Period::Month => "%Y-%m",
+        };
+
+        format!("strftime('{}', {})", format, time)
+    }
+}
+
+impl FromStr for Period {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "run" => Ok(Period::Run),
+            "day" => Ok(Period::Day),
+            "week" => Ok(Period::Week),
+            "month" => Ok(Period::Month),
+            _ => Err(format!(
+                "unsupported period {}, expected one of {}",
+                s,
+                Period::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// A command's runs in one period, averaged, oldest period first.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PerfSummary {
+    pub period: String,
+    pub runs: i64,
+    pub wall_time: i64,
+    pub user_time: Option<i64>,
+    pub sys_time: Option<i64>,
+    // the most any run needed, not the average
+    pub max_rss: Option<i64>,
+    // how the average wall time compares to the period before, in percent
+    pub change: Option<f64>,
+    pub regression: bool,
+}
+
+impl Tabular for PerfSummary {
+    fn headers() -> Vec<&'static str> {
+        vec![
+            "period",
+            "runs",
+            "wall",
+            "user",
+            "sys",
+            "max_rss_kb",
+            "change",
+            "regression",
+        ]
+    }
+
+    fn cells(&self) -> Vec<Value> {
+        vec![
+            Value::Text(self.period.clone()),
+            Value::Int(self.runs),
+            Value::Duration(self.wall_time),
+            Value::Duration(self.user_time.unwrap_or(-1)),
+            Value::Duration(self.sys_time.unwrap_or(-1)),
+            self.max_rss.map_or(Value::Null, Value::Int),
+            self.change.map_or(Value::Null, Value::Float),
+            Value::Text(if self.regression { "yes" } else { "" }.to_string()),
+        ]
+    }
+}
+
+/// Fill in `change` and flag every period that got more than `threshold`
+/// percent slower than the one before it.
+pub fn mark_regressions(periods: &mut [PerfSummary], threshold: f64) {
+    for idx in 1..periods.len() {
+        let before = periods[idx - 1].wall_time;
+        if before <= 0 {
+            continue;
+        }
+
+        let change = (periods[idx].wall_time - before) as f64 * 100.0 / before as f64;
+        periods[idx].change = Some(change);
+        periods[idx].regression = change > threshold;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mark_regressions() {
+        let period = |wall_time| PerfSummary {
+            period: String::new(),
+            runs: 1,
+            wall_time,
+            user_time: None,
+            sys_time: None,
+            max_rss: None,
+            change: None,
+            regression: false,
+        };
+        let mut periods = vec![period(100), period(110), period(165), period(66)];
+        mark_regressions(&mut periods, 20.0);
+
+        let changes: Vec<Option<f64>> = periods.iter().map(|p| p.change).collect();
+        assert_eq!(changes, vec![None, Some(10.0), Some(50.0), Some(-60.0)]);
+        let flagged: Vec<bool> = periods.iter().map(|p| p.regression).collect();
+        assert_eq!(flagged, vec![false, false, true, false]);
+    }
+}
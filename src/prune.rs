@@ -0,0 +1,94 @@
+use crate::filter::{ExitFilter, HistoryFilter};
+use crate::output::{Tabular, Value};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+// the [retention] table in the config file, nothing is pruned unless
+// something in here is set
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RetentionSettings {
+    // drop everything older than this many days
+    pub days: Option<i64>,
+    // keep at most this many commands, the newest ones
+    pub max_rows: Option<i64>,
+    // drop failed commands older than this many days
+    pub failed_days: Option<i64>,
+    // keep only the newest copy of every command, like list --unique
+    pub dedupe: bool,
+}
+
+/// How many commands one retention rule dropped, or would drop.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Pruned {
+    pub rule: String,
+    pub rows: u64,
+}
+
+impl Tabular for Pruned {
+    fn headers() -> Vec<&'static str> {
+        vec!["rule", "rows"]
+    }
+
+    fn cells(&self) -> Vec<Value> {
+        vec![Value::Text(self.rule.clone()), Value::Int(self.rows as i64)]
+    }
+}
+
+pub enum Rule {
+    // drop the commands that match
+    Drop(HistoryFilter),
+    // drop everything but the commands that match
+    Keep(HistoryFilter),
+}
+
+impl RetentionSettings {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Every rule that's set, in the order they're applied. max_rows goes
+    /// last so it counts what the others left.
+    pub fn rules(&self, now: DateTime<Utc>) -> Vec<(String, Rule)> {
+        let mut rules = vec![];
+
+        if let Some(days) = self.days {
+            let filter = HistoryFilter {
+                until: Some(now - Duration::days(days)),
+                ..Default::default()
+            };
+            rules.push((format!("older than {} days", days), Rule::Drop(filter)));
+        }
+        if let Some(days) = self.failed_days {
+            let filter = HistoryFilter {
+                exit: Some(ExitFilter::Failure),
+                until: Some(now - Duration::days(days)),
+                ..Default::default()
+            };
+            rules.push((
+                format!("failed more than {} days ago", days),
+                Rule::Drop(filter),
+            ));
+        }
+        if self.dedupe {
+            // the copy list --unique would show
+            let filter = HistoryFilter {
+                unique: true,
+                ..Default::default()
+            };
+            rules.push(("older copies".to_string(), Rule::Keep(filter)));
+        }
+        if let Some(max_rows) = self.max_rows {
+            let filter = HistoryFilter {
+                limit: Some(max_rows),
+                ..Default::default()
+            };
+            rules.push((
+                format!("more than {} commands", max_rows),
+                Rule::Keep(filter),
+            ));
+        }
+
+        rules
+    }
+}
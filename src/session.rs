@@ -0,0 +1,88 @@
+use crate::output::{Tabular, Value};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+/// The session every history item without a better one belongs to, e.g.
+/// items saved before sessions existed or an import with no session ids.
+pub const UNKNOWN_SESSION_ID: i64 = 0;
+
+/// One terminal, from the shell starting to it exiting. `session_id` is the
+/// local number history_items point at, `uuid` is the id that's the same on
+/// every machine and what the shell hooks export as $HIZTERY_SESSION.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub session_id: Option<i64>,
+    pub uuid: String,
+    pub hostname: String,
+    pub username: String,
+    pub shell: String,
+    pub tty: String,
+    pub start_time: DateTime<Utc>,
+    pub end_time: Option<DateTime<Utc>>,
+}
+
+impl Session {
+    /// A session starting now on this machine. UUIDv7 starts with the time,
+    /// so they sort the way they were created.
+    pub fn new(shell: &str, tty: &str) -> Self {
+        Self {
+            session_id: None,
+            uuid: new_uuid(),
+            hostname: hostname().to_string(),
+            username: username().to_string(),
+            shell: shell.to_string(),
+            tty: tty.to_string(),
+            start_time: Utc::now(),
+            end_time: None,
+        }
+    }
+}
+
+pub fn new_uuid() -> String {
+    uuid::Uuid::now_v7().to_string()
+}
+
+// Both are looked up once, an import saves a lot of items in one go.
+pub fn hostname() -> &'static str {
+    static HOSTNAME: OnceLock<String> = OnceLock::new();
+    HOSTNAME.get_or_init(|| whoami::fallible::hostname().unwrap_or_default())
+}
+
+pub fn username() -> &'static str {
+    static USERNAME: OnceLock<String> = OnceLock::new();
+    USERNAME.get_or_init(whoami::username)
+}
+
+impl Tabular for Session {
+    fn headers() -> Vec<&'static str> {
+        vec![
+            "session_id",
+            "uuid",
+            "hostname",
+            "username",
+            "shell",
+            "tty",
+            "start_time",
+            "end_time",
+        ]
+    }
+
+    fn cells(&self) -> Vec<Value> {
+        vec![
+            self.session_id.map_or(Value::Null, Value::Int),
+            Value::Text(self.uuid.clone()),
+            Value::Text(self.hostname.clone()),
+            Value::Text(self.username.clone()),
+            Value::Text(self.shell.clone()),
+            Value::Text(self.tty.clone()),
+            Value::Date(self.start_time),
+            self.end_time.map_or(Value::Null, Value::Date),
+        ]
+    }
+
+    // the uuid is what every other command takes
+    fn plain(&self) -> String {
+        self.uuid.clone()
+    }
+}
@@ -0,0 +1,135 @@
+use crate::ignore::IgnoreSettings;
+use crate::prune::RetentionSettings;
+use crate::scrub::ScrubSettings;
+use anyhow::Context;
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const DB_ENV_VAR: &str = "HIZTERY_DB";
+pub const CONFIG_ENV_VAR: &str = "HIZTERY_CONFIG";
+
+// Everything in here is optional so an empty or missing config file
+// behaves exactly like the defaults
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub db_path: Option<PathBuf>,
+    // host:port of the server `hiztery sync` talks to
+    pub sync_address: Option<String>,
+    pub scrub: ScrubSettings,
+    pub ignore: IgnoreSettings,
+    pub retention: RetentionSettings,
+    // also log to hiztery.log next to the database, -v does too
+    pub log_file: bool,
+}
+
+impl Settings {
+    // $HIZTERY_CONFIG wins, otherwise ~/.config/hiztery/config.toml or
+    // whatever the platform equivalent is
+    pub fn config_path() -> Option<PathBuf> {
+        match env::var_os(CONFIG_ENV_VAR) {
+            Some(path) => Some(PathBuf::from(path)),
+            None => dirs::config_dir().map(|dir| dir.join("hiztery").join("config.toml")),
+        }
+    }
+
+    pub fn load() -> anyhow::Result<Self> {
+        let path = match Self::config_path() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(Self::default()),
+        };
+
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read config file {:?}", path))?;
+        let settings = toml::from_str(&contents)
+            .with_context(|| format!("failed to parse config file {:?}", path))?;
+
+        Ok(settings)
+    }
+
+    // the database location is resolved in this order:
+    // --db flag, $HIZTERY_DB, db_path in the config file, then the data dir
+    pub fn db_path(&self, flag: Option<PathBuf>) -> PathBuf {
+        resolve_db_path(
+            flag,
+            env::var_os(DB_ENV_VAR).map(PathBuf::from),
+            self.db_path.clone(),
+        )
+    }
+}
+
+fn resolve_db_path(
+    flag: Option<PathBuf>,
+    env: Option<PathBuf>,
+    config: Option<PathBuf>,
+) -> PathBuf {
+    // an empty value, like HIZTERY_DB= in a shell, is the same as not set
+    let set = |path: Option<PathBuf>| path.filter(|p| !p.as_os_str().is_empty());
+
+    set(flag)
+        .or_else(|| set(env))
+        .or_else(|| set(config))
+        .map(|path| expand_tilde(&path))
+        .unwrap_or_else(default_db_path)
+}
+
+pub fn default_db_path() -> PathBuf {
+    dirs::data_dir()
+        .map(|dir| dir.join("hiztery"))
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("history.db")
+}
+
+fn expand_tilde(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_db_path_precedence() {
+        let flag = Some(PathBuf::from("/flag.db"));
+        let env = Some(PathBuf::from("/env.db"));
+        let config = Some(PathBuf::from("/config.db"));
+
+        assert_eq!(
+            resolve_db_path(flag, env.clone(), config.clone()),
+            PathBuf::from("/flag.db")
+        );
+        assert_eq!(
+            resolve_db_path(None, env, config.clone()),
+            PathBuf::from("/env.db")
+        );
+        assert_eq!(
+            resolve_db_path(None, None, config),
+            PathBuf::from("/config.db")
+        );
+        assert_eq!(resolve_db_path(None, None, None), default_db_path());
+        assert_eq!(
+            resolve_db_path(
+                None,
+                Some(PathBuf::new()),
+                Some(PathBuf::from("/config.db"))
+            ),
+            PathBuf::from("/config.db")
+        );
+    }
+
+    #[test]
+    fn test_config_file_db_path() {
+        let settings: Settings = toml::from_str("db_path = \"~/history/hiztery.db\"").unwrap();
+        let path = settings.db_path.unwrap();
+
+        assert_eq!(path, PathBuf::from("~/history/hiztery.db"));
+        if let Some(home) = dirs::home_dir() {
+            assert_eq!(expand_tilde(&path), home.join("history/hiztery.db"));
+        }
+    }
+}
@@ -0,0 +1,76 @@
+# hiztery bash integration, add this to ~/.bashrc:
+#
+#   eval "$(hiztery init bash)"
+
+__hiztery_id=""
+__hiztery_armed=0
+__hiztery_cpu=()
+
+# one session per shell, subshells and anything started from it inherit it
+export HIZTERY_SESSION
+HIZTERY_SESSION=$(hiztery --quiet sessions start --shell bash --tty "$(tty 2>/dev/null)")
+
+# The user and sys time of every child the shell has waited for, fields 16
+# and 17 of /proc/$$/stat in 1/100s. Only linux has it, elsewhere commands
+# are saved without cpu times. Read without a subshell, a subshell would
+# start counting from zero.
+__hiztery_cputimes() {
+    local line
+    [[ -r /proc/$$/stat ]] || return 1
+    read -r line < /proc/$$/stat
+    read -r -a __hiztery_stat <<< "${line##*\) }"
+}
+
+# The DEBUG trap runs before every simple command, so only the first one
+# after a prompt is recorded. PROMPT_COMMAND, completions and key bindings
+# are skipped.
+__hiztery_preexec() {
+    [[ -n "${COMP_LINE:-}" || -n "${READLINE_POINT:-}" ]] && return
+    [[ "$__hiztery_armed" != 1 ]] && return
+    [[ "$BASH_COMMAND" == __hiztery_precmd* ]] && return
+    __hiztery_armed=0
+
+    local cmd
+    # only strip the history number, a leading space is how a command asks
+    # not to be recorded
+    cmd=$(HISTTIMEFORMAT='' builtin history 1 | sed -e 's/^ *[0-9]*[* ] //')
+    __hiztery_id=$(hiztery --quiet start --cwd "$PWD" --command "$cmd")
+    __hiztery_cpu=()
+    __hiztery_cputimes && __hiztery_cpu=("${__hiztery_stat[13]}" "${__hiztery_stat[14]}")
+}
+
+__hiztery_precmd() {
+    local exit=$?
+    __hiztery_armed=0
+    if [[ -n "$__hiztery_id" ]]; then
+        local -a perf=()
+        if [[ ${#__hiztery_cpu[@]} -eq 2 ]] && __hiztery_cputimes; then
+            perf=(--user-time $(( (__hiztery_stat[13] - __hiztery_cpu[0]) * 10000000 ))
+                --sys-time $(( (__hiztery_stat[14] - __hiztery_cpu[1]) * 10000000 )))
+        fi
+        hiztery --quiet end --id "$__hiztery_id" --exit "$exit" "${perf[@]}"
+        __hiztery_id=""
+    fi
+}
+
+__hiztery_arm() {
+    __hiztery_armed=1
+}
+
+__hiztery_exit() {
+    hiztery --quiet sessions end
+}
+
+__hiztery_search() {
+    local selected
+    selected=$(hiztery --quiet search --interactive --mode z --query "$READLINE_LINE")
+    if [[ -n "$selected" ]]; then
+        READLINE_LINE=$selected
+        READLINE_POINT=${#selected}
+    fi
+}
+
+trap '__hiztery_preexec' DEBUG
+trap '__hiztery_exit' EXIT
+PROMPT_COMMAND="__hiztery_precmd${PROMPT_COMMAND:+;$PROMPT_COMMAND};__hiztery_arm"
+bind -x '"\C-r": __hiztery_search'
@@ -0,0 +1,50 @@
+# hiztery fish integration, add this to ~/.config/fish/config.fish:
+#
+#   hiztery init fish | source
+
+set -gx HIZTERY_SESSION (hiztery --quiet sessions start --shell fish --tty (tty 2>/dev/null))
+
+# The user and sys time of every child fish has waited for, fields 16 and 17
+# of /proc/$fish_pid/stat in 1/100s. Only linux has it, elsewhere commands are
+# saved without cpu times.
+function _hiztery_cputimes
+    test -r /proc/$fish_pid/stat; or return 1
+    set -l fields (string replace -r '^.*\) ' '' < /proc/$fish_pid/stat | string split ' ')
+    printf '%s\n' $fields[14] $fields[15]
+end
+
+function _hiztery_preexec --on-event fish_preexec
+    set -g _hiztery_id (hiztery --quiet start --cwd $PWD --command "$argv[1]")
+    set -g _hiztery_cpu (_hiztery_cputimes)
+end
+
+function _hiztery_postexec --on-event fish_postexec
+    set -l exit $status
+    if test -n "$_hiztery_id"
+        set -l perf
+        set -l cpu (_hiztery_cputimes)
+        if test (count $_hiztery_cpu) -eq 2 -a (count $cpu) -eq 2
+            set perf --user-time (math "($cpu[1] - $_hiztery_cpu[1]) * 10000000") \
+                --sys-time (math "($cpu[2] - $_hiztery_cpu[2]) * 10000000")
+        end
+        hiztery --quiet end --id $_hiztery_id --exit $exit $perf
+        set -e _hiztery_id
+    end
+end
+
+function _hiztery_exit --on-event fish_exit
+    hiztery --quiet sessions end
+end
+
+function _hiztery_search
+    set -l selected (hiztery --quiet search --interactive --mode z --query (commandline -b))
+    if test -n "$selected"
+        commandline -r -- $selected
+    end
+    commandline -f repaint
+end
+
+bind \cr _hiztery_search
+if bind -M insert >/dev/null 2>&1
+    bind -M insert \cr _hiztery_search
+end
@@ -0,0 +1,172 @@
+use crate::output::{self, OutputFormat, Tabular, Value};
+use serde::Serialize;
+use std::io::{self, Write};
+
+// strftime's %w counts from sunday, the heatmap starts the week on monday
+pub const DAYS: &[&str] = &["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const HOURS: &[&str] = &[
+    "00", "01", "02", "03", "04", "05", "06", "07", "08", "09", "10", "11", "12", "13", "14", "15",
+    "16", "17", "18", "19", "20", "21", "22", "23",
+];
+
+/// Everything `hiztery stats` reports, for whatever the filter let through.
+#[derive(Debug, Default, Serialize)]
+pub struct Stats {
+    pub total: i64,
+    pub commands: Vec<Top>,
+    // the first word of every command, which is usually the binary
+    pub binaries: Vec<Top>,
+    pub directories: Vec<Top>,
+    // one row per weekday, local time
+    pub heatmap: Vec<HeatmapRow>,
+    // by p95 duration
+    pub slowest: Vec<CommandStats>,
+    // by failure rate
+    pub flakiest: Vec<CommandStats>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Top {
+    pub name: String,
+    pub count: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HeatmapRow {
+    pub day: &'static str,
+    pub hours: Vec<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CommandStats {
+    pub command: String,
+    pub runs: i64,
+    // nanoseconds over the runs that reported one, -1 if none did
+    pub avg_duration: i64,
+    pub p95_duration: i64,
+    pub failed: i64,
+    // of the runs that reported an exit status
+    pub failure_rate: f64,
+}
+
+impl Tabular for Top {
+    fn headers() -> Vec<&'static str> {
+        vec!["name", "count"]
+    }
+
+    fn cells(&self) -> Vec<Value> {
+        vec![Value::Text(self.name.clone()), Value::Int(self.count)]
+    }
+}
+
+impl Tabular for HeatmapRow {
+    fn headers() -> Vec<&'static str> {
+        let mut headers = vec!["day"];
+        headers.extend(HOURS);
+        headers
+    }
+
+    fn cells(&self) -> Vec<Value> {
+        let mut cells = vec![Value::Text(self.day.to_string())];
+        cells.extend(self.hours.iter().map(|c| Value::Int(*c)));
+        cells
+    }
+}
+
+impl Tabular for CommandStats {
+    fn headers() -> Vec<&'static str> {
+        vec!["command", "runs", "avg", "p95", "failed", "failure_rate"]
+    }
+
+    fn cells(&self) -> Vec<Value> {
+        vec![
+            Value::Text(self.command.clone()),
+            Value::Int(self.runs),
+            Value::Duration(self.avg_duration),
+            Value::Duration(self.p95_duration),
+            Value::Int(self.failed),
+            Value::Float(self.failure_rate),
+        ]
+    }
+}
+
+/// Stats don't fit in one table, so it's one JSON object or a table per
+/// section.
+pub fn print(stats: &Stats, format: OutputFormat) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    out.write_all(render(stats, format)?.as_bytes())?;
+    out.flush()
+}
+
+pub fn render(stats: &Stats, format: OutputFormat) -> io::Result<String> {
+    match format {
+        OutputFormat::Json => {
+            let mut json = serde_json::to_string_pretty(stats)?;
+            json.push('\n');
+            Ok(json)
+        }
+        OutputFormat::Table => {
+            let sections = [
+                ("top commands", output::render(&stats.commands, format)?),
+                ("top binaries", output::render(&stats.binaries, format)?),
+                (
+                    "busiest directories",
+                    output::render(&stats.directories, format)?,
+                ),
+                ("commands per hour", output::render(&stats.heatmap, format)?),
+                ("slowest commands", output::render(&stats.slowest, format)?),
+                (
+                    "flakiest commands",
+                    output::render(&stats.flakiest, format)?,
+                ),
+            ];
+
+            let mut rendered = format!("{} commands\n", stats.total);
+            for (title, table) in sections.iter() {
+                rendered += &format!("\n{}\n{}", title, table);
+            }
+            Ok(rendered)
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "stats can only be printed as a table or json",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_stats() {
+        let stats = Stats {
+            total: 3,
+            commands: vec![Top {
+                name: "cargo test".to_string(),
+                count: 3,
+            }],
+            heatmap: DAYS
+                .iter()
+                .map(|day| HeatmapRow {
+                    day,
+                    hours: vec![0; 24],
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        let table = render(&stats, OutputFormat::Table).unwrap();
+        assert!(table.starts_with("3 commands\n\ntop commands\nname"));
+        assert!(table.contains("day  00  01"));
+        assert!(table.contains("\nsun  0   0"));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&render(&stats, OutputFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["commands"][0]["name"], "cargo test");
+        assert_eq!(json["heatmap"][6]["hours"].as_array().unwrap().len(), 24);
+
+        assert!(render(&stats, OutputFormat::Csv).is_err());
+    }
+}
@@ -0,0 +1,420 @@
+use crate::database::{Database, Sqlite};
+use crate::encryption::{EncryptedItem, Key};
+use crate::history_item::HistoryItem;
+use crate::session::{Session, UNKNOWN_SESSION_ID};
+use async_std::io::{prelude::*, BufReader};
+use async_std::net::{TcpListener, TcpStream};
+use async_std::task;
+use futures::StreamExt;
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::{Entry, HashMap};
+
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:4040";
+
+// how many items go over the wire in one message
+const BATCH_SIZE: i64 = 1000;
+
+// The protocol is one JSON message per line. The client sends a request and
+// waits for the response before sending the next one. Items only ever travel
+// encrypted, the server stores records it can't read.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request {
+    // who the server is, the first thing a client asks
+    Hello,
+    Push { records: Vec<EncryptedItem> },
+    // records the server stored with an id greater than `after`
+    Pull { after: i64, limit: i64 },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Response {
+    Hello { server_id: String },
+    Pushed { count: usize },
+    Records { records: Vec<EncryptedItem> },
+    Error { message: String },
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct SyncStats {
+    pub pushed: usize,
+    pub pulled: usize,
+    // records pulled that didn't decrypt with our key
+    pub skipped: usize,
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            reader: BufReader::new(stream.clone()),
+            writer: stream,
+        }
+    }
+
+    async fn send<T: Serialize>(&mut self, msg: &T) -> Result<(), sqlx::Error> {
+        let mut line = serde_json::to_vec(msg).map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+        line.push(b'\n');
+        self.writer.write_all(&line).await?;
+        self.writer.flush().await?;
+
+        Ok(())
+    }
+
+    // None once the other side hung up
+    async fn recv<T: DeserializeOwned>(&mut self) -> Result<Option<T>, sqlx::Error> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+
+        let msg = serde_json::from_str(&line).map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+        Ok(Some(msg))
+    }
+
+    async fn request(&mut self, req: &Request) -> Result<Response, sqlx::Error> {
+        self.send(req).await?;
+        match self.recv().await? {
+            Some(Response::Error { message }) => Err(sqlx::Error::Protocol(message)),
+            Some(res) => Ok(res),
+            None => Err(sqlx::Error::Protocol(
+                "sync server closed the connection".to_string(),
+            )),
+        }
+    }
+}
+
+/// Accept sync clients until the listener fails. Every client gets its own
+/// task, they all share `db`.
+pub async fn serve(db: Sqlite, listener: TcpListener) -> Result<(), sqlx::Error> {
+    debug!("sync server listening on {:?}", listener.local_addr()?);
+
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = stream?;
+        let db = db.clone();
+
+        task::spawn(async move {
+            let peer = stream.peer_addr().ok();
+            debug!("sync client connected from {:?}", peer);
+            if let Err(e) = handle(db, stream).await {
+                warn!("sync with {:?} failed: {}", peer, e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn handle(mut db: Sqlite, stream: TcpStream) -> Result<(), sqlx::Error> {
+    let mut conn = Connection::new(stream);
+
+    while let Some(req) = conn.recv::<Request>().await? {
+        let res = match req {
+            Request::Hello => db
+                .server_id()
+                .await
+                .map(|server_id| Response::Hello { server_id }),
+            Request::Push { records } => db
+                .save_records(&records)
+                .await
+                .map(|count| Response::Pushed { count }),
+            Request::Pull { after, limit } => db
+                .records_after(after, limit.clamp(1, BATCH_SIZE))
+                .await
+                .map(|records| Response::Records { records }),
+        };
+
+        let res = res.unwrap_or_else(|e| Response::Error {
+            message: e.to_string(),
+        });
+        conn.send(&res).await?;
+    }
+
+    Ok(())
+}
+
+// A command that's still running gets pushed without its exit status and
+// duration. The high-water mark stops in front of it so it goes out again
+// next time, and the server fills in the rest once it's known. After a day
+// it's taken to have never reported back.
+fn push_mark(last_pushed: i64, batch: &[HistoryItem]) -> (i64, bool) {
+    let cutoff = chrono::Utc::now() - chrono::Duration::days(1);
+    let mut mark = last_pushed;
+
+    for item in batch {
+        if item.duration < 0 && item.exit_status < 0 && item.timestamp > cutoff {
+            return (mark, true);
+        }
+        mark = item.history_id.unwrap_or(mark);
+    }
+
+    (mark, false)
+}
+
+/// Push everything the server hasn't seen from us yet, then pull everything
+/// we haven't seen from it. Both directions pick up where the last sync with
+/// the server at `address` left off, the high-water marks are kept per
+/// server, by the id it tells us and not by how it was reached. What was
+/// pulled from a server isn't pushed back to it. Every machine syncing
+/// through the same server needs the same `key`.
+pub async fn sync(db: &mut Sqlite, address: &str, key: &Key) -> Result<SyncStats, sqlx::Error> {
+    let mut conn = Connection::new(TcpStream::connect(address).await?);
+    let server = match conn.request(&Request::Hello).await? {
+        Response::Hello { server_id } => server_id,
+        res => return Err(unexpected(res)),
+    };
+    // syncs from before servers had ids kept their marks under the address
+    db.rename_sync_host(address, &server).await?;
+
+    let (mut last_pushed, mut last_pulled) = db.sync_state(&server).await?;
+    let mut stats = SyncStats::default();
+    // our session_id to the session, and the other way around for what's
+    // pulled, by uuid
+    let mut sessions: HashMap<i64, Option<Session>> = HashMap::new();
+    let mut session_ids: HashMap<String, i64> = HashMap::new();
+    debug!(
+        "syncing with {} ({}), last pushed {}, last pulled {}",
+        address, server, last_pushed, last_pulled
+    );
+
+    loop {
+        let batch = db.after(last_pushed, BATCH_SIZE).await?;
+        if batch.is_empty() {
+            break;
+        }
+
+        let (mark, held_back) = push_mark(last_pushed, &batch);
+        let newest = batch.last().and_then(|i| i.history_id).unwrap_or(mark);
+        let pulled = db.pulled_ids(&server, last_pushed, newest).await?;
+        let mut records = Vec::with_capacity(batch.len());
+        for item in &batch {
+            if item.history_id.is_some_and(|id| pulled.contains(&id)) {
+                continue;
+            }
+            if let Entry::Vacant(e) = sessions.entry(item.session_id) {
+                e.insert(db.session(&item.session_id.to_string()).await?);
+            }
+            let session = sessions[&item.session_id].as_ref();
+            records.push(key.encrypt(item, session)?);
+        }
+        if !records.is_empty() {
+            match conn.request(&Request::Push { records }).await? {
+                Response::Pushed { count } => stats.pushed += count,
+                res => return Err(unexpected(res)),
+            }
+        }
+
+        // anything past a running command was sent but will be sent again
+        last_pushed = mark;
+        if held_back {
+            break;
+        }
+    }
+
+    loop {
+        let req = Request::Pull {
+            after: last_pulled,
+            limit: BATCH_SIZE,
+        };
+        let records = match conn.request(&req).await? {
+            Response::Records { records } => records,
+            res => return Err(unexpected(res)),
+        };
+        if records.is_empty() {
+            break;
+        }
+
+        last_pulled = records
+            .iter()
+            .filter_map(|r| r.record_id)
+            .max()
+            .unwrap_or(last_pulled);
+        let mut items = Vec::with_capacity(records.len());
+        for record in &records {
+            // another key, or somebody tampered with it. Either way there's
+            // nothing to be done about it, and it mustn't hold up the rest.
+            let (mut item, session) = match key.decrypt(record) {
+                Ok(decrypted) => decrypted,
+                Err(e) => {
+                    warn!(
+                        "skipping record {:?} from {}: {}",
+                        record.record_id, address, e
+                    );
+                    stats.skipped += 1;
+                    continue;
+                }
+            };
+            item.session_id = match session {
+                Some(session) => match session_ids.get(&session.uuid) {
+                    Some(id) => *id,
+                    None => {
+                        let id = db.save_session(&session).await?;
+                        session_ids.insert(session.uuid, id);
+                        id
+                    }
+                },
+                None => UNKNOWN_SESSION_ID,
+            };
+            items.push(item);
+        }
+
+        db.save_pulled(&items, &server).await?;
+        stats.pulled += items.len();
+    }
+
+    db.set_sync_state(&server, last_pushed, last_pulled).await?;
+    debug!("synced with {}: {:?}", address, stats);
+
+    Ok(stats)
+}
+
+fn unexpected(res: Response) -> sqlx::Error {
+    sqlx::Error::Protocol(format!("unexpected response from sync server: {:?}", res))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::filter::HistoryFilter;
+    use chrono::Utc;
+
+    async fn db_with(commands: &[&str]) -> Sqlite {
+        let mut db = Sqlite::new("sqlite::memory:").await.unwrap();
+        for (idx, cmd) in commands.iter().enumerate() {
+            let item = HistoryItem::new(
+                None,
+                cmd.to_string(),
+                "/home/ellie".to_string(),
+                1_000,
+                0,
+                Some(1),
+                Utc::now() - chrono::Duration::seconds(100 - idx as i64),
+            );
+            db.save(&item).await.unwrap();
+        }
+        db
+    }
+
+    async fn commands(db: &Sqlite) -> Vec<String> {
+        let mut commands: Vec<String> = db
+            .query(&HistoryFilter::default())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|i| i.command)
+            .collect();
+        commands.sort();
+        commands
+    }
+
+    #[async_std::test]
+    async fn test_sync_between_two_hosts() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = db_with(&[]).await;
+        task::spawn(serve(server.clone(), listener));
+
+        let key = Key::generate();
+        let mut laptop = db_with(&["ls", "cargo build"]).await;
+        let mut desktop = db_with(&["pwd"]).await;
+
+        let stats = sync(&mut laptop, &address, &key).await.unwrap();
+        assert_eq!(stats.pushed, 2);
+
+        sync(&mut desktop, &address, &key).await.unwrap();
+        sync(&mut laptop, &address, &key).await.unwrap();
+
+        let everything = vec!["cargo build", "ls", "pwd"];
+        assert_eq!(commands(&laptop).await, everything);
+        assert_eq!(commands(&desktop).await, everything);
+
+        // both had a session 1, laptop's comes over as a session of its own
+        let uuid = laptop.session("1").await.unwrap().unwrap().uuid;
+        let session = desktop.session(&uuid).await.unwrap().unwrap();
+        assert_ne!(session.session_id, Some(1));
+        let pulled = desktop
+            .query(&HistoryFilter {
+                session_id: session.session_id,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(pulled.len(), 2);
+
+        // the server only has records it can't read
+        assert_eq!(server.history_count().await.unwrap(), 0);
+        let records = server.records_after(0, 10).await.unwrap();
+        assert_eq!(records.len(), 3);
+        assert!(records.iter().all(|r| !r.data.contains("cargo")));
+
+        // nothing new anywhere, and what desktop pulled isn't sent back
+        let server_id = server.server_id().await.unwrap();
+        let pulled = desktop.pulled_ids(&server_id, 0, i64::MAX).await.unwrap();
+        assert_eq!(pulled.len(), 2);
+        let stats = sync(&mut desktop, &address, &key).await.unwrap();
+        assert_eq!((stats.pushed, stats.pulled), (0, 0));
+
+        // the same server by another name is still the same server
+        let port = address.rsplit(':').next().unwrap();
+        let stats = sync(&mut desktop, &format!("localhost:{}", port), &key)
+            .await
+            .unwrap();
+        assert_eq!((stats.pushed, stats.pulled), (0, 0));
+
+        // marks from before servers had ids are picked up from the address
+        let mut old = db_with(&[]).await;
+        old.set_sync_state(&address, 0, 3).await.unwrap();
+        let stats = sync(&mut old, &address, &key).await.unwrap();
+        assert_eq!((stats.pushed, stats.pulled), (0, 0));
+        assert_eq!(old.sync_state(&server_id).await.unwrap(), (0, 3));
+
+        // another server gets all of it, the marks are per server
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let other = listener.local_addr().unwrap().to_string();
+        task::spawn(serve(db_with(&[]).await, listener));
+        let stats = sync(&mut desktop, &other, &key).await.unwrap();
+        assert_eq!((stats.pushed, stats.pulled), (3, 3));
+
+        // a machine with another key can't make sense of any of it, but
+        // doesn't get stuck on it either
+        let mut stranger = db_with(&[]).await;
+        let stats = sync(&mut stranger, &address, &Key::generate())
+            .await
+            .unwrap();
+        assert_eq!((stats.pulled, stats.skipped), (0, 3));
+        assert!(commands(&stranger).await.is_empty());
+        let stats = sync(&mut stranger, &address, &Key::generate())
+            .await
+            .unwrap();
+        assert_eq!(stats.skipped, 0);
+    }
+
+    #[test]
+    fn test_push_mark_stops_at_running_command() {
+        let item = |id, duration| {
+            HistoryItem::new(
+                Some(id),
+                "sleep 10".to_string(),
+                "/".to_string(),
+                duration,
+                if duration < 0 { -1 } else { 0 },
+                Some(1),
+                Utc::now(),
+            )
+        };
+
+        assert_eq!(push_mark(0, &[item(1, 5), item(2, 5)]), (2, false));
+        assert_eq!(
+            push_mark(0, &[item(1, 5), item(2, -1), item(3, 5)]),
+            (1, true)
+        );
+    }
+}
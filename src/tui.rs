@@ -0,0 +1,560 @@
+use crate::database::{Database, SearchMode};
+use crate::filter::{CwdFilter, HistoryFilter};
+use crate::history_item::HistoryItem;
+use crate::output::{format_age, format_duration, format_exit_status};
+use chrono::Utc;
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Cell, Paragraph, Row, Table, TableState};
+use ratatui::{Frame, Terminal};
+use std::io;
+
+// the modes tab cycles through, regex is left out because a half typed
+// regex is usually an invalid one
+const MODES: &[SearchMode] = &[SearchMode::Prefix, SearchMode::FullText, SearchMode::Fuzzy];
+
+// where the commands come from, ctrl-s cycles through these
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scope {
+    Everywhere,
+    // everywhere, but what ran here and in the parent directories first
+    HereFirst,
+    Below,
+    Here,
+}
+
+const SCOPES: &[Scope] = &[
+    Scope::Everywhere,
+    Scope::HereFirst,
+    Scope::Below,
+    Scope::Here,
+];
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Scope::Everywhere => "everywhere",
+            Scope::HereFirst => "here first",
+            Scope::Below => "below here",
+            Scope::Here => "here",
+        };
+        f.write_str(name)
+    }
+}
+
+const DEFAULT_LIMIT: i64 = 200;
+const CWD_WIDTH: usize = 30;
+
+// Puts the terminal back the way we found it, also when drawing fails
+struct TerminalGuard;
+
+impl TerminalGuard {
+    fn new() -> io::Result<Self> {
+        enable_raw_mode()?;
+        execute!(io::stderr(), EnterAlternateScreen)?;
+        Ok(Self)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = execute!(io::stderr(), LeaveAlternateScreen);
+        let _ = disable_raw_mode();
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    None,
+    Search,
+    Select,
+    Cancel,
+}
+
+struct Picker {
+    query: String,
+    // in chars, not bytes
+    cursor: usize,
+    mode: SearchMode,
+    limit: i64,
+    filter: HistoryFilter,
+    scope: Scope,
+    // the directory the scope is about
+    dir: String,
+    // every hit carries the char offsets that matched, only fuzzy has any
+    hits: Vec<(HistoryItem, Vec<usize>)>,
+    error: Option<String>,
+    state: TableState,
+}
+
+/// Run the full screen picker and return the command that was chosen, if any.
+/// `dir` is where the shell is, the cwd and rank in `filter` pick the scope
+/// it starts out with.
+///
+/// The picker draws on stderr so stdout is left for the chosen command, which
+/// is what the shell hooks read back.
+pub async fn pick(
+    db: &impl Database,
+    search_mode: SearchMode,
+    limit: Option<i64>,
+    query: String,
+    filter: HistoryFilter,
+    dir: String,
+) -> Result<Option<String>, sqlx::Error> {
+    let mut picker = Picker::new(search_mode, limit.unwrap_or(DEFAULT_LIMIT), query);
+    picker.set_filter(filter, dir);
+    picker.refresh(db).await;
+
+    let _guard = TerminalGuard::new()?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stderr()))?;
+
+    loop {
+        terminal.draw(|frame| picker.draw(frame))?;
+
+        let key = match event::read()? {
+            Event::Key(key) if key.kind == KeyEventKind::Press => key,
+            _ => continue,
+        };
+
+        match picker.handle_key(key) {
+            Action::Select => return Ok(picker.selected()),
+            Action::Cancel => return Ok(None),
+            Action::Search => picker.refresh(db).await,
+            Action::None => {}
+        }
+    }
+}
+
+impl Picker {
+    fn new(mode: SearchMode, limit: i64, query: String) -> Self {
+        Self {
+            cursor: query.chars().count(),
+            query,
+            mode,
+            limit,
+            filter: HistoryFilter::default(),
+            scope: Scope::Everywhere,
+            dir: String::new(),
+            hits: vec![],
+            error: None,
+            state: TableState::default(),
+        }
+    }
+
+    fn set_filter(&mut self, filter: HistoryFilter, dir: String) {
+        (self.scope, self.dir) = match &filter.cwd {
+            Some(CwdFilter::Exact(d)) => (Scope::Here, d.clone()),
+            Some(CwdFilter::Tree(d)) => (Scope::Below, d.clone()),
+            None if filter.near.is_some() => (Scope::HereFirst, dir),
+            None => (Scope::Everywhere, dir),
+        };
+        self.filter = filter;
+    }
+
+    // the filter with the scope's cwd and rank in place of the original ones
+    fn scoped_filter(&self) -> HistoryFilter {
+        let (cwd, near) = match self.scope {
+            Scope::Everywhere => (None, None),
+            Scope::HereFirst => (None, Some(self.dir.clone())),
+            Scope::Below => (Some(CwdFilter::Tree(self.dir.clone())), None),
+            Scope::Here => (Some(CwdFilter::Exact(self.dir.clone())), None),
+        };
+
+        HistoryFilter {
+            cwd,
+            near,
+            limit: Some(self.limit),
+            ..self.filter.clone()
+        }
+    }
+
+    async fn refresh(&mut self, db: &impl Database) {
+        let filter = self.scoped_filter();
+        let res = match self.mode {
+            SearchMode::Fuzzy => db
+                .fuzzy_search(&self.query, &filter)
+                .await
+                .map(|m| m.into_iter().map(|m| (m.item, m.positions)).collect()),
+            mode => db
+                .search(mode, &self.query, &filter)
+                .await
+                .map(|h| h.into_iter().map(|h| (h, vec![])).collect()),
+        };
+
+        match res {
+            Ok(hits) => {
+                self.hits = hits;
+                self.error = None;
+            }
+            Err(e) => {
+                self.hits.clear();
+                self.error = Some(e.to_string());
+            }
+        }
+
+        self.state
+            .select(if self.hits.is_empty() { None } else { Some(0) });
+    }
+
+    fn selected(&self) -> Option<String> {
+        self.state
+            .selected()
+            .and_then(|idx| self.hits.get(idx))
+            .map(|(h, _)| h.command.clone())
+    }
+
+    fn byte_cursor(&self) -> usize {
+        self.query
+            .char_indices()
+            .nth(self.cursor)
+            .map_or(self.query.len(), |(idx, _)| idx)
+    }
+
+    fn next_mode(&mut self) {
+        let idx = MODES.iter().position(|m| *m == self.mode);
+        self.mode = match idx {
+            Some(idx) => MODES[(idx + 1) % MODES.len()],
+            None => MODES[0],
+        };
+    }
+
+    fn next_scope(&mut self) {
+        let idx = SCOPES.iter().position(|s| *s == self.scope).unwrap_or(0);
+        self.scope = SCOPES[(idx + 1) % SCOPES.len()];
+    }
+
+    fn move_selection(&mut self, offset: isize) {
+        if self.hits.is_empty() {
+            return;
+        }
+
+        let current = self.state.selected().unwrap_or(0) as isize;
+        let last = self.hits.len() as isize - 1;
+        self.state
+            .select(Some((current + offset).clamp(0, last) as usize));
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Action {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+
+        match key.code {
+            KeyCode::Esc => Action::Cancel,
+            KeyCode::Char('c') | KeyCode::Char('g') if ctrl => Action::Cancel,
+            KeyCode::Enter => Action::Select,
+            KeyCode::Tab => {
+                self.next_mode();
+                Action::Search
+            }
+            KeyCode::Char('r') if ctrl => {
+                self.next_mode();
+                Action::Search
+            }
+            KeyCode::Char('s') if ctrl => {
+                self.next_scope();
+                Action::Search
+            }
+            KeyCode::Up => {
+                self.move_selection(-1);
+                Action::None
+            }
+            KeyCode::Char('p') | KeyCode::Char('k') if ctrl => {
+                self.move_selection(-1);
+                Action::None
+            }
+            KeyCode::Down => {
+                self.move_selection(1);
+                Action::None
+            }
+            KeyCode::Char('n') | KeyCode::Char('j') if ctrl => {
+                self.move_selection(1);
+                Action::None
+            }
+            KeyCode::PageUp => {
+                self.move_selection(-10);
+                Action::None
+            }
+            KeyCode::PageDown => {
+                self.move_selection(10);
+                Action::None
+            }
+            KeyCode::Left => {
+                self.cursor = self.cursor.saturating_sub(1);
+                Action::None
+            }
+            KeyCode::Right => {
+                self.cursor = (self.cursor + 1).min(self.query.chars().count());
+                Action::None
+            }
+            KeyCode::Home => {
+                self.cursor = 0;
+                Action::None
+            }
+            KeyCode::Char('a') if ctrl => {
+                self.cursor = 0;
+                Action::None
+            }
+            KeyCode::End => {
+                self.cursor = self.query.chars().count();
+                Action::None
+            }
+            KeyCode::Char('e') if ctrl => {
+                self.cursor = self.query.chars().count();
+                Action::None
+            }
+            KeyCode::Char('u') if ctrl => {
+                self.query.clear();
+                self.cursor = 0;
+                Action::Search
+            }
+            KeyCode::Char('w') if ctrl => {
+                let end = self.byte_cursor();
+                let start = self.query[..end]
+                    .trim_end()
+                    .rfind(char::is_whitespace)
+                    .map_or(0, |idx| idx + 1);
+                self.cursor -= self.query[start..end].chars().count();
+                self.query.replace_range(start..end, "");
+                Action::Search
+            }
+            KeyCode::Backspace => {
+                if self.cursor == 0 {
+                    return Action::None;
+                }
+                self.cursor -= 1;
+                let idx = self.byte_cursor();
+                self.query.remove(idx);
+                Action::Search
+            }
+            KeyCode::Delete => {
+                if self.cursor >= self.query.chars().count() {
+                    return Action::None;
+                }
+                let idx = self.byte_cursor();
+                self.query.remove(idx);
+                Action::Search
+            }
+            KeyCode::Char(c) if !ctrl => {
+                let idx = self.byte_cursor();
+                self.query.insert(idx, c);
+                self.cursor += 1;
+                Action::Search
+            }
+            _ => Action::None,
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [input_area, results_area] =
+            Layout::vertical([Constraint::Length(3), Constraint::Min(0)]).areas(frame.area());
+
+        let input = Paragraph::new(self.query.as_str()).block(
+            Block::default().borders(Borders::ALL).title(format!(
+                " {} | {} | tab: mode  ctrl-s: scope  enter: select  esc: cancel ",
+                self.mode, self.scope
+            )),
+        );
+        frame.render_widget(input, input_area);
+        frame.set_cursor_position((
+            input_area.x + 1 + self.query[..self.byte_cursor()].chars().count() as u16,
+            input_area.y + 1,
+        ));
+
+        let now = Utc::now();
+        let rows = self.hits.iter().map(|(h, positions)| {
+            let exit_style = if h.exit_status == 0 {
+                Style::default()
+            } else {
+                Style::default().fg(Color::Red)
+            };
+
+            Row::new(vec![
+                Cell::from(format_age(now - h.timestamp)),
+                Cell::from(format_duration(h.duration)),
+                Cell::from(format_exit_status(h.exit_status)).style(exit_style),
+                Cell::from(shorten_cwd(&h.cwd)),
+                Cell::from(highlight(&h.command, positions)),
+            ])
+        });
+
+        let title = match &self.error {
+            Some(e) => format!(" {} ", e),
+            None => format!(" {} results ", self.hits.len()),
+        };
+
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(4),
+                Constraint::Length(8),
+                Constraint::Length(4),
+                Constraint::Length(CWD_WIDTH as u16),
+                Constraint::Min(10),
+            ],
+        )
+        .header(
+            Row::new(vec!["age", "duration", "exit", "cwd", "command"])
+                .style(Style::default().add_modifier(Modifier::BOLD)),
+        )
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+        .highlight_symbol("> ");
+
+        frame.render_stateful_widget(table, results_area, &mut self.state);
+    }
+}
+
+// Split the command into spans so the fuzzy matched characters stand out.
+// Newlines would make the row taller than one line so they become spaces,
+// which keeps the char offsets lined up.
+fn highlight(command: &str, positions: &[usize]) -> Line<'static> {
+    let matched = Style::default()
+        .fg(Color::Yellow)
+        .add_modifier(Modifier::BOLD);
+
+    let mut spans = vec![];
+    let mut current = String::new();
+    let mut current_matched = false;
+
+    for (idx, c) in command.chars().enumerate() {
+        let c = if c == '\n' { ' ' } else { c };
+        let is_matched = positions.contains(&idx);
+        if is_matched != current_matched && !current.is_empty() {
+            let text = std::mem::take(&mut current);
+            spans.push(if current_matched {
+                Span::styled(text, matched)
+            } else {
+                Span::raw(text)
+            });
+        }
+        current_matched = is_matched;
+        current.push(c);
+    }
+
+    if !current.is_empty() {
+        spans.push(if current_matched {
+            Span::styled(current, matched)
+        } else {
+            Span::raw(current)
+        });
+    }
+
+    Line::from(spans)
+}
+
+// keep the end of long paths, that's the part that tells them apart
+fn shorten_cwd(cwd: &str) -> String {
+    let cwd = match dirs::home_dir().and_then(|home| {
+        cwd.strip_prefix(home.to_string_lossy().as_ref())
+            .map(|rest| format!("~{}", rest))
+    }) {
+        Some(cwd) => cwd,
+        None => cwd.to_string(),
+    };
+
+    let len = cwd.chars().count();
+    if len <= CWD_WIDTH {
+        cwd
+    } else {
+        let tail: String = cwd.chars().skip(len - (CWD_WIDTH - 1)).collect();
+        format!("…{}", tail)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn ctrl(c: char) -> KeyEvent {
+        KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL)
+    }
+
+    #[test]
+    fn test_picker_editing() {
+        let mut picker = Picker::new(SearchMode::Fuzzy, 10, "git".to_string());
+
+        assert_eq!(picker.handle_key(key(KeyCode::Char(' '))), Action::Search);
+        assert_eq!(picker.handle_key(key(KeyCode::Char('p'))), Action::Search);
+        assert_eq!(picker.query, "git p");
+
+        picker.handle_key(key(KeyCode::Home));
+        picker.handle_key(key(KeyCode::Delete));
+        assert_eq!(picker.query, "it p");
+
+        picker.handle_key(key(KeyCode::End));
+        picker.handle_key(ctrl('w'));
+        assert_eq!(picker.query, "it ");
+        assert_eq!(picker.cursor, 3);
+
+        picker.handle_key(ctrl('u'));
+        assert_eq!(picker.query, "");
+        assert_eq!(picker.handle_key(key(KeyCode::Backspace)), Action::None);
+
+        assert_eq!(picker.handle_key(key(KeyCode::Esc)), Action::Cancel);
+        assert_eq!(picker.handle_key(key(KeyCode::Enter)), Action::Select);
+    }
+
+    #[test]
+    fn test_picker_mode_cycle() {
+        let mut picker = Picker::new(SearchMode::Prefix, 10, String::new());
+
+        picker.handle_key(key(KeyCode::Tab));
+        assert_eq!(picker.mode, SearchMode::FullText);
+        picker.handle_key(key(KeyCode::Tab));
+        assert_eq!(picker.mode, SearchMode::Fuzzy);
+        picker.handle_key(ctrl('r'));
+        assert_eq!(picker.mode, SearchMode::Prefix);
+
+        let mut picker = Picker::new(SearchMode::Regex, 10, String::new());
+        picker.handle_key(key(KeyCode::Tab));
+        assert_eq!(picker.mode, SearchMode::Prefix);
+    }
+
+    #[test]
+    fn test_picker_scope_cycle() {
+        let mut picker = Picker::new(SearchMode::Prefix, 10, String::new());
+        let filter = HistoryFilter {
+            near: Some("/src".to_string()),
+            ..Default::default()
+        };
+        picker.set_filter(filter, "/src/hiztery".to_string());
+        assert_eq!(picker.scope, Scope::HereFirst);
+        assert_eq!(
+            picker.scoped_filter().near,
+            Some("/src/hiztery".to_string())
+        );
+
+        assert_eq!(picker.handle_key(ctrl('s')), Action::Search);
+        assert_eq!(
+            picker.scoped_filter().cwd,
+            Some(CwdFilter::Tree("/src/hiztery".to_string()))
+        );
+        picker.handle_key(ctrl('s'));
+        assert_eq!(picker.scope, Scope::Here);
+        picker.handle_key(ctrl('s'));
+        let filter = picker.scoped_filter();
+        assert_eq!((filter.cwd, filter.near), (None, None));
+        assert_eq!(filter.limit, Some(10));
+
+        // a --cwd somewhere else is what the scopes are about
+        let filter = HistoryFilter {
+            cwd: Some(CwdFilter::Exact("/etc".to_string())),
+            ..Default::default()
+        };
+        picker.set_filter(filter, "/src/hiztery".to_string());
+        assert_eq!(picker.scope, Scope::Here);
+        picker.handle_key(ctrl('s'));
+        picker.handle_key(ctrl('s'));
+        assert_eq!(picker.scoped_filter().near, Some("/etc".to_string()));
+    }
+}